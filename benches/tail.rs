@@ -0,0 +1,83 @@
+//! Tail-read benchmark on a large synthetic Chatfile.
+//!
+//! Run with `cargo bench --bench tail`. Set `CF_BENCH_MB` to change the
+//! size of the generated room (default 256 MB).
+
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::time::{Duration, Instant};
+
+use chatfiles::core::{Chatfile, clear};
+
+const ITERATIONS: u32 = 20;
+
+fn main() {
+    let mb: u64 = std::env::var("CF_BENCH_MB")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(256);
+
+    let path =
+        std::env::temp_dir().join(format!("chatfiles_bench_{}.Chatfile", std::process::id()));
+    generate(&path, mb * 1024 * 1024);
+    let cf = Chatfile::open(&path).unwrap();
+    let size = cf.size().unwrap();
+
+    println!("Chatfile: {} ({} MB)", path.display(), size / (1024 * 1024));
+
+    let full = bench("full scan (baseline)", || {
+        let reader = BufReader::new(File::open(&path).unwrap());
+        let lines: Vec<String> = reader.lines().map(|l| l.unwrap()).collect();
+        lines.len()
+    });
+    let tail = bench("read_last(20)", || cf.read_last(20).unwrap().len());
+    bench("last_line()", || {
+        cf.last_line().unwrap().map_or(0, |l| l.len())
+    });
+    bench("read_from(end - 4 KB)", || {
+        cf.read_from(size.saturating_sub(4096)).unwrap().0.len()
+    });
+    bench("size()", || cf.size().unwrap() as usize);
+
+    println!(
+        "read_last(20) is {:.0}x faster than a full scan",
+        full.as_secs_f64() / tail.as_secs_f64()
+    );
+
+    clear::remove_room(&path).unwrap();
+}
+
+fn generate(path: &std::path::Path, bytes: u64) {
+    let mut out = BufWriter::new(File::create(path).unwrap());
+    writeln!(
+        out,
+        "[system 2026-01-01 00:00:00]: Chatroom \"bench\". Format: Name: msg. Append only."
+    )
+    .unwrap();
+
+    let mut written = 0;
+    let mut i = 0u64;
+    while written < bytes {
+        let line = format!(
+            "agent-{}: message number {i} with some padding text\n",
+            i % 16
+        );
+        out.write_all(line.as_bytes()).unwrap();
+        written += line.len() as u64;
+        i += 1;
+    }
+    out.flush().unwrap();
+}
+
+fn bench(label: &str, mut f: impl FnMut() -> usize) -> Duration {
+    // Warm-up run so allocator and page cache effects of the previous
+    // benchmark don't end up in this one
+    let mut sink = f();
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        sink += f();
+    }
+    let per_iter = start.elapsed() / ITERATIONS;
+    println!("{label:<24} {per_iter:>12.2?} / iter  (checksum {sink})");
+    per_iter
+}
@@ -1,159 +1,747 @@
-use crate::core::{self, Chatfile};
+use std::path::Path;
+use std::time::{Duration, SystemTime};
 
-pub fn create_room(name: Option<&str>) -> i32 {
-    match Chatfile::create(name) {
+use serde_json::json;
+
+use super::json;
+use crate::core::acl::Role;
+use crate::core::message::Moderation;
+use crate::core::ops::{AwaitFilter, Stale};
+use crate::core::protect::Protection;
+use crate::core::{self, Chatfile, Error, Message, RoomOptions, SessionSelector};
+
+/// Reports an error and returns its exit code (see `Error::exit_code`).
+fn fail(e: impl Into<Error>, json: bool) -> i32 {
+    let e = e.into();
+    if json {
+        json::print(&json::error(&e));
+    } else {
+        eprintln!("{e}");
+    }
+    e.exit_code()
+}
+
+pub fn create_room(name: Option<&str>, options: RoomOptions, json: bool) -> i32 {
+    match Chatfile::create_with(name, options) {
         Ok(cf) => {
-            println!("Created room: {}", cf.path.display());
+            if json {
+                json::print(&json!({ "room": json::path(&cf.path) }));
+            } else {
+                println!("Created room: {}", cf.path.display());
+            }
             0
         }
-        Err(e) => {
-            eprintln!("{e}");
-            1
-        }
+        Err(e) => fail(e, json),
     }
 }
 
-pub fn list_rooms() -> i32 {
+pub fn list_rooms(json: bool) -> i32 {
     match Chatfile::list_rooms() {
         Ok(rooms) => {
+            if json {
+                json::print(&json!({ "rooms": json::paths(&rooms) }));
+                return 0;
+            }
             println!("Available rooms:");
             for room in rooms {
                 println!("  {}", room.display());
             }
             0
         }
-        Err(e) => {
-            eprintln!("{e}");
-            1
-        }
+        Err(e) => fail(e, json),
     }
 }
 
-pub fn register(chatfile: &str, name: Option<&str>) -> i32 {
+pub fn register(chatfile: &str, name: Option<&str>, json: bool) -> i32 {
     match core::ops::register(chatfile, name) {
         Ok(session) => {
-            println!("{}", session.name);
+            if json {
+                json::print(&json!({
+                    "name": session.name,
+                    "chatfile": json::path(&session.chatfile),
+                }));
+            } else {
+                println!("{}", session.name);
+            }
             0
         }
-        Err(e) => {
-            eprintln!("{e}");
-            1
+        Err(e) => fail(e, json),
+    }
+}
+
+pub fn join(selector: &SessionSelector, json: bool) -> i32 {
+    match core::ops::join(selector) {
+        Ok(session) => {
+            if json {
+                json::print(&json!({ "name": session.name, "joined": true }));
+            } else {
+                println!("Joined as {}", session.name);
+            }
+            0
         }
+        Err(e) => fail(e, json),
     }
 }
 
-pub fn join() -> i32 {
-    match core::ops::join() {
+pub fn leave(selector: &SessionSelector, json: bool) -> i32 {
+    match core::ops::leave(selector) {
         Ok(session) => {
-            println!("Joined as {}", session.name);
+            if json {
+                json::print(&json!({ "name": session.name, "joined": false }));
+            } else {
+                println!("Left room");
+            }
             0
         }
-        Err(e) => {
-            eprintln!("{e}");
-            1
+        Err(e) => fail(e, json),
+    }
+}
+
+/// Resolves a message argument, reading the body from stdin for `-`.
+/// Multi-line bodies are escaped onto a single Chatfile line on send.
+fn message_arg(message: &str) -> std::io::Result<String> {
+    if message != "-" {
+        return Ok(message.to_string());
+    }
+
+    let mut body = String::new();
+    std::io::Read::read_to_string(&mut std::io::stdin(), &mut body)?;
+    // Drop the newline that ends piped input, keep any inner ones
+    if body.ends_with('\n') {
+        body.pop();
+        if body.ends_with('\r') {
+            body.pop();
         }
     }
+    Ok(body)
 }
 
-pub fn leave() -> i32 {
-    match core::ops::leave() {
-        Ok(_) => {
-            println!("Left room");
+/// Prints Chatfile lines with escaped line breaks expanded.
+fn print_lines(lines: &[String], json: bool) {
+    if json {
+        json::print(&json!({ "messages": json::messages(lines) }));
+        return;
+    }
+    for line in lines {
+        println!("{}", Message::parse(line).to_plain());
+    }
+}
+
+/// Like `print_lines`, prefixing each line with `#id`.
+fn print_numbered(lines: &[(u64, String)], json: bool) {
+    if json {
+        json::print(&json!({ "messages": json::numbered(lines) }));
+        return;
+    }
+    for (id, line) in lines {
+        println!("#{id} {}", Message::parse(line).to_plain());
+    }
+}
+
+/// Acknowledges a command that prints nothing on success.
+fn done(json: bool) -> i32 {
+    if json {
+        json::print(&json!({ "ok": true }));
+    }
+    0
+}
+
+pub fn send(selector: &SessionSelector, message: &str, reply_to: Option<u64>, json: bool) -> i32 {
+    let message = match message_arg(message) {
+        Ok(m) => m,
+        Err(e) => return fail(e, json),
+    };
+
+    let result = match reply_to {
+        Some(id) => core::ops::reply(selector, id, &message),
+        None => core::ops::send(selector, &message),
+    };
+
+    match result {
+        Ok(()) => done(json),
+        Err(e) => fail(e, json),
+    }
+}
+
+pub fn admin_send(selector: &SessionSelector, message: &str, json: bool) -> i32 {
+    let message = match message_arg(message) {
+        Ok(m) => m,
+        Err(e) => return fail(e, json),
+    };
+
+    match core::ops::admin_send(selector, &message) {
+        Ok(()) => done(json),
+        Err(e) => fail(e, json),
+    }
+}
+
+pub fn moderate(selector: &SessionSelector, name: &str, action: Moderation, json: bool) -> i32 {
+    match core::ops::moderate(selector, name, action) {
+        Ok(()) => done(json),
+        Err(e) => fail(e, json),
+    }
+}
+
+pub fn mute(selector: &SessionSelector, name: &str, duration: Option<Duration>, json: bool) -> i32 {
+    match core::ops::mute(selector, name, duration) {
+        Ok(()) => done(json),
+        Err(e) => fail(e, json),
+    }
+}
+
+pub fn await_message(
+    selector: &SessionSelector,
+    filter: &AwaitFilter,
+    timeout: Option<Duration>,
+    poll: bool,
+    json: bool,
+) -> i32 {
+    let result = if poll {
+        core::ops::poll_filtered(selector, filter)
+    } else {
+        core::ops::await_filtered(selector, filter, timeout)
+    };
+
+    match result {
+        Ok(lines) => {
+            print_lines(&lines, json);
             0
         }
-        Err(e) => {
-            eprintln!("{e}");
-            1
+        Err(e) => fail(e, json),
+    }
+}
+
+pub fn await_rooms(
+    selector: &SessionSelector,
+    rooms: &[String],
+    filter: &AwaitFilter,
+    timeout: Option<Duration>,
+    json: bool,
+) -> i32 {
+    match core::ops::await_rooms(selector, rooms, filter, timeout) {
+        Ok(found) => {
+            if json {
+                json::print(&json!({
+                    "room": json::path(&found.room),
+                    "messages": json::messages(&found.lines),
+                }));
+                return 0;
+            }
+            let room = found.room.display();
+            for line in &found.lines {
+                println!("{room}: {}", Message::parse(line).to_plain());
+            }
+            0
         }
+        Err(e) => fail(e, json),
     }
 }
 
-pub fn send(message: &str) -> i32 {
-    match core::ops::send(message) {
-        Ok(()) => 0,
-        Err(e) => {
-            eprintln!("{e}");
-            1
+pub fn send_await(selector: &SessionSelector, message: &str, json: bool) -> i32 {
+    let message = match message_arg(message) {
+        Ok(m) => m,
+        Err(e) => return fail(e, json),
+    };
+    if let Err(e) = core::ops::send(selector, &message) {
+        return fail(e, json);
+    }
+    await_message(selector, &AwaitFilter::default(), None, false, json)
+}
+
+pub fn read(selector: &SessionSelector, n: usize, new: bool, ids: bool, json: bool) -> i32 {
+    if ids {
+        return match core::ops::read_numbered(selector, n) {
+            Ok(lines) => {
+                print_numbered(&lines, json);
+                0
+            }
+            Err(e) => fail(e, json),
+        };
+    }
+
+    let result = if new {
+        core::ops::read_new(selector)
+    } else {
+        core::ops::read(selector, n)
+    };
+
+    match result {
+        Ok(lines) => {
+            print_lines(&lines, json);
+            0
         }
+        Err(e) => fail(e, json),
     }
 }
 
-pub fn admin_send(message: &str) -> i32 {
-    match core::ops::admin_send(message) {
-        Ok(()) => 0,
+pub fn read_thread(selector: &SessionSelector, id: u64, json: bool) -> i32 {
+    match core::ops::read_thread(selector, id) {
+        Ok(lines) => {
+            print_numbered(&lines, json);
+            0
+        }
+        Err(e) => fail(e, json),
+    }
+}
+
+pub fn status(selector: &SessionSelector, json: bool) -> i32 {
+    let (session, room) = match core::ops::status(selector) {
+        Ok(s) => s,
+        Err(e) => return fail(e, json),
+    };
+
+    if json {
+        let room = room.as_ref();
+        let limits = room.map(|r| &r.limits).ok();
+        json::print(&json!({
+            "name": session.name,
+            "chatfile": json::path(&session.chatfile),
+            "joined": session.joined,
+            "room_error": room.err().map(|e| e.to_string()),
+            "role": room.ok().and_then(|r| r.role).map(|r| r.as_str()),
+            "rate_limit": limits.and_then(|l| l.rate_limit.map(|r| json!({
+                "messages": r.messages,
+                "window_secs": r.window.as_secs_f64(),
+                "used": l.used,
+            }))),
+            "max_length": limits.and_then(|l| l.max_length),
+            "protection": room.ok().map(|r| r.protection.as_str()),
+            "tampered": room.ok().and_then(|r| r.tampered.as_deref()),
+        }));
+        return 0;
+    }
+    println!("Session: {}", session.name);
+    println!("Chatfile: {}", session.chatfile.display());
+    println!("Joined: {}", if session.joined { "yes" } else { "no" });
+
+    let room = match room {
+        Ok(room) => room,
         Err(e) => {
-            eprintln!("{e}");
-            1
+            println!("Room: unavailable ({e})");
+            return 0;
+        }
+    };
+    if let Some(role) = room.role {
+        println!("Role: {role}");
+    }
+    let limits = &room.limits;
+    if let Some(rate) = limits.rate_limit {
+        println!("Rate limit: {rate} ({} used)", limits.used);
+    }
+    if let Some(max) = limits.max_length {
+        println!("Max length: {max}");
+    }
+    match room.protection {
+        Protection::AppendOnly => println!("Protection: append-only (kernel flag)"),
+        Protection::Snapshot => {
+            println!("Protection: snapshot (replacing or truncating the room is recorded)")
         }
     }
+    if let Some(tampered) = &room.tampered {
+        println!("Tampered: {tampered} (cf verify --accept to acknowledge)");
+    }
+    0
+}
+
+pub fn roles(selector: &SessionSelector, json: bool) -> i32 {
+    let roles = match core::ops::roles(selector) {
+        Ok(r) => r,
+        Err(e) => return fail(e, json),
+    };
+
+    if json {
+        let roles: Vec<_> = roles
+            .iter()
+            .map(|(name, role)| json!({ "name": name, "role": role.as_str() }))
+            .collect();
+        json::print(&json!({ "roles": roles }));
+        return 0;
+    }
+
+    if roles.is_empty() {
+        println!("No roles: everyone is a member. Run: cf role grant <name> owner");
+        return 0;
+    }
+    for (name, role) in &roles {
+        println!("{name}  {role}");
+    }
+    0
 }
 
-pub fn await_message() -> i32 {
-    match core::ops::await_message() {
-        Ok(msg) => {
-            println!("{msg}");
+pub fn grant_role(selector: &SessionSelector, name: &str, role: Role, json: bool) -> i32 {
+    match core::ops::grant_role(selector, name, role) {
+        Ok(()) => {
+            if json {
+                return done(json);
+            }
+            println!("{name} is now {role}");
             0
         }
-        Err(e) => {
-            eprintln!("{e}");
-            1
+        Err(e) => fail(e, json),
+    }
+}
+
+pub fn revoke_role(selector: &SessionSelector, name: &str, json: bool) -> i32 {
+    match core::ops::revoke_role(selector, name) {
+        Ok(revoked) => {
+            if json {
+                json::print(&json!({ "revoked": revoked }));
+            } else if revoked {
+                println!("{name} is a member again");
+            } else {
+                println!("{name} has no role");
+            }
+            0
         }
+        Err(e) => fail(e, json),
     }
 }
 
-pub fn send_await(message: &str) -> i32 {
-    if send(message) != 0 {
-        return 1;
+pub fn sessions(selector: &SessionSelector, json: bool) -> i32 {
+    let (entries, active) = match core::ops::sessions(selector) {
+        Ok(s) => s,
+        Err(e) => return fail(e, json),
+    };
+
+    if json {
+        let sessions: Vec<_> = entries
+            .iter()
+            .map(|e| {
+                json!({
+                    "id": e.id,
+                    "name": e.session.name,
+                    "chatfile": json::path(&e.session.chatfile),
+                    "joined": e.session.joined,
+                    "active": active.as_ref() == Some(&e.path),
+                })
+            })
+            .collect();
+        json::print(&json!({ "sessions": sessions }));
+        return 0;
     }
-    await_message()
+
+    if entries.is_empty() {
+        println!("No sessions. Run: cf register <chatfile>");
+        return 0;
+    }
+    for e in &entries {
+        let marker = if active.as_ref() == Some(&e.path) {
+            '*'
+        } else {
+            ' '
+        };
+        let joined = if e.session.joined { "joined" } else { "-" };
+        println!(
+            "{marker} {}  {}  {}  {joined}",
+            e.id,
+            e.session.name,
+            e.session.chatfile.display()
+        );
+    }
+    0
 }
 
-pub fn read(n: usize) -> i32 {
-    match core::ops::read(n) {
+/// Formats how long ago `time` was, e.g. `42s`, `5m` or `3h`.
+fn ago(time: SystemTime) -> String {
+    let secs = time.elapsed().unwrap_or_default().as_secs();
+    match secs {
+        0..60 => format!("{secs}s"),
+        60..3600 => format!("{}m", secs / 60),
+        3600..86400 => format!("{}h", secs / 3600),
+        _ => format!("{}d", secs / 86400),
+    }
+}
+
+pub fn who(selector: &SessionSelector, chatfile: Option<&Path>, json: bool) -> i32 {
+    let members = match core::ops::who(selector, chatfile) {
+        Ok(m) => m,
+        Err(e) => return fail(e, json),
+    };
+
+    if json {
+        let members: Vec<_> = members
+            .iter()
+            .map(|m| {
+                let seen = m
+                    .last_seen
+                    .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok());
+                json!({
+                    "name": m.name,
+                    "status": m.status.as_str(),
+                    "last_seen": seen.map(|d| d.as_secs()),
+                })
+            })
+            .collect();
+        json::print(&json!({ "members": members }));
+        return 0;
+    }
+
+    if members.is_empty() {
+        println!("Nobody here.");
+        return 0;
+    }
+    for m in &members {
+        let seen = match m.last_seen {
+            Some(time) => format!("seen {} ago", ago(time)),
+            None => "no heartbeat".to_string(),
+        };
+        println!("{}  {}  {seen}", m.name, m.status.as_str());
+    }
+    0
+}
+
+pub fn verify(
+    selector: &SessionSelector,
+    chatfile: Option<&Path>,
+    accept: bool,
+    json: bool,
+) -> i32 {
+    if accept {
+        return match core::ops::accept(selector, chatfile) {
+            Ok(violation) => {
+                if json {
+                    json::print(&json!({ "ok": true, "accepted": violation }));
+                } else {
+                    match violation {
+                        Some(v) => println!("Accepted the room as it is now: {v}"),
+                        None => println!("Nothing to accept: no replacement or truncation seen"),
+                    }
+                }
+                0
+            }
+            Err(e) => fail(e, json),
+        };
+    }
+    match core::ops::verify(selector, chatfile) {
         Ok(lines) => {
-            for line in lines {
-                println!("{line}");
+            if json {
+                json::print(&json!({ "ok": true, "chained": lines.is_some(), "lines": lines }));
+            } else {
+                match lines {
+                    Some(lines) => println!("Chain intact: {lines} lines"),
+                    None => println!(
+                        "Not replaced or truncated (no hash chain, so edits that keep the size go unnoticed)"
+                    ),
+                }
             }
             0
         }
-        Err(e) => {
-            eprintln!("{e}");
-            1
+        Err(e) => fail(e, json),
+    }
+}
+
+pub fn members(selector: &SessionSelector, chatfile: Option<&Path>, json: bool) -> i32 {
+    let roster = match core::ops::members(selector, chatfile) {
+        Ok(r) => r,
+        Err(e) => return fail(e, json),
+    };
+
+    if json {
+        let members: Vec<_> = roster
+            .iter()
+            .map(|m| {
+                json!({
+                    "name": m.name,
+                    "joined_at": m.joined_at.map(|ts| ts.format("%F %T").to_string()),
+                    "joined_id": m.joined_id,
+                })
+            })
+            .collect();
+        json::print(&json!({ "members": members }));
+        return 0;
+    }
+
+    if roster.is_empty() {
+        println!("Nobody has joined.");
+        return 0;
+    }
+    for m in &roster {
+        match m.joined_at {
+            Some(ts) => println!(
+                "{}  joined {} (#{})",
+                m.name,
+                ts.format("%F %T"),
+                m.joined_id
+            ),
+            None => println!("{}  joined at #{}", m.name, m.joined_id),
         }
     }
+    0
 }
 
-pub fn status() -> i32 {
-    match core::ops::status() {
+pub fn heartbeat(selector: &SessionSelector, json: bool) -> i32 {
+    match core::ops::heartbeat(selector) {
+        Ok(_) => done(json),
+        Err(e) => fail(e, json),
+    }
+}
+
+pub fn unregister(selector: &SessionSelector, json: bool) -> i32 {
+    match core::ops::unregister(selector) {
         Ok(session) => {
-            println!("Session: {}", session.name);
-            println!("Chatfile: {}", session.chatfile.display());
-            println!("Joined: {}", if session.joined { "yes" } else { "no" });
+            if json {
+                json::print(&json!({
+                    "name": session.name,
+                    "chatfile": json::path(&session.chatfile),
+                }));
+            } else {
+                println!("Unregistered {}", session.name);
+            }
             0
         }
-        Err(e) => {
-            eprintln!("{e}");
-            1
+        Err(e) => fail(e, json),
+    }
+}
+
+pub fn rename(selector: &SessionSelector, name: &str, json: bool) -> i32 {
+    match core::ops::rename(selector, name) {
+        Ok(session) => {
+            if json {
+                json::print(&json!({
+                    "name": session.name,
+                    "chatfile": json::path(&session.chatfile),
+                }));
+            } else {
+                println!("{}", session.name);
+            }
+            0
+        }
+        Err(e) => fail(e, json),
+    }
+}
+
+pub fn gc_sessions(idle: Option<Duration>, dry_run: bool, json: bool) -> i32 {
+    let stale = match core::ops::gc_sessions(idle, dry_run) {
+        Ok(s) => s,
+        Err(e) => return fail(e, json),
+    };
+
+    let reason = |stale: &Stale| match stale {
+        Stale::MissingChatfile => "chatfile missing".to_string(),
+        Stale::Idle(age) if age.as_secs() >= 3600 => format!("idle {}h", age.as_secs() / 3600),
+        Stale::Idle(age) => format!("idle {}m", age.as_secs() / 60),
+    };
+
+    if json {
+        let removed: Vec<_> = stale
+            .iter()
+            .map(|(e, why)| {
+                json!({
+                    "id": e.id,
+                    "name": e.session.name,
+                    "chatfile": json::path(&e.session.chatfile),
+                    "reason": match why {
+                        Stale::MissingChatfile => "chatfile_missing",
+                        Stale::Idle(_) => "idle",
+                    },
+                })
+            })
+            .collect();
+        json::print(&json!({ "removed": removed, "dry_run": dry_run }));
+        return 0;
+    }
+
+    if stale.is_empty() {
+        println!("No stale sessions.");
+        return 0;
+    }
+    let verb = if dry_run { "Would remove" } else { "Removed" };
+    for (e, why) in &stale {
+        println!(
+            "{verb} {}  {}  {} ({})",
+            e.id,
+            e.session.name,
+            e.session.chatfile.display(),
+            reason(why)
+        );
+    }
+    0
+}
+
+/// Quotes `s` for a POSIX shell.
+fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+pub fn use_session(selector: &SessionSelector, shell: bool, unset: bool, json: bool) -> i32 {
+    if unset {
+        return match core::ops::unuse_session() {
+            Ok(removed) => {
+                if json {
+                    json::print(&json!({ "unpinned": removed }));
+                } else if removed {
+                    println!("Unpinned session from this directory");
+                } else {
+                    println!("No session pinned to this directory");
+                }
+                0
+            }
+            Err(e) => fail(e, json),
+        };
+    }
+
+    let result = if shell {
+        core::Session::resolve(selector)
+    } else {
+        core::ops::use_session(selector)
+    };
+
+    match result {
+        Ok(entry) => {
+            if json {
+                json::print(&json!({
+                    "id": entry.id,
+                    "name": entry.session.name,
+                    "chatfile": json::path(&entry.session.chatfile),
+                    "path": json::path(&entry.path),
+                }));
+            } else if shell {
+                let path = entry.path.display().to_string();
+                println!("export CF_SESSION={}", shell_quote(&path));
+            } else {
+                println!(
+                    "Using {} in {} here",
+                    entry.session.name,
+                    entry.session.chatfile.display()
+                );
+            }
+            0
         }
+        Err(e) => fail(e, json),
     }
 }
 
-pub fn clear(force: bool, sessions_only: bool) -> i32 {
+/// Deletes Chatfiles and session data. With `--json` there is no prompt:
+/// without `--force` it only reports what would be deleted.
+pub fn clear(force: bool, sessions_only: bool, dry_run: bool, json: bool) -> i32 {
     let files = match core::ops::list_clearable_files(sessions_only) {
         Ok(f) => f,
-        Err(e) => {
-            eprintln!("{e}");
-            return 1;
-        }
+        Err(e) => return fail(e, json),
     };
 
-    if files.is_empty() {
+    if json && (dry_run || !force) {
+        json::print(&json!({
+            "chatfiles": json::paths(&files.chatfiles),
+            "sidecars": json::paths(&files.sidecars),
+            "sessions": json::paths(&files.sessions),
+            "admin": files.admin.as_deref().map(json::path),
+        }));
+        return 0;
+    }
+
+    if files.is_empty() && !json {
         println!("No files to clear.");
         return 0;
     }
 
-    println!("The following files will be PERMANENTLY DELETED:");
-    println!("{}", core::ops::format_file_list(&files));
+    if !json {
+        println!("The following files will be PERMANENTLY DELETED:");
+        println!("{}", core::ops::format_file_list(&files));
+    }
+
+    if dry_run {
+        return 0;
+    }
 
     if !force {
         use std::io::Write;
@@ -170,6 +758,20 @@ pub fn clear(force: bool, sessions_only: bool) -> i32 {
     }
 
     let result = core::ops::clear_files(&files);
+    let code = if result.failed.is_empty() { 0 } else { 1 };
+
+    if json {
+        let failed: Vec<_> = result
+            .failed
+            .iter()
+            .map(|(path, err)| json!({ "path": json::path(path), "error": err }))
+            .collect();
+        json::print(&json!({
+            "removed": json::paths(&result.removed),
+            "failed": failed,
+        }));
+        return code;
+    }
 
     for path in &result.removed {
         println!("[OK] Removed: {}", path.display());
@@ -181,24 +783,20 @@ pub fn clear(force: bool, sessions_only: bool) -> i32 {
 
     if result.failed.is_empty() {
         println!("\nCleared {} files.", result.removed.len());
-        0
     } else {
         eprintln!(
             "\nPartially cleared: {} removed, {} failed.",
             result.removed.len(),
             result.failed.len()
         );
-        1
     }
+    code
 }
 
 #[cfg(feature = "web")]
-pub fn serve(port: u16, dir: &str) -> i32 {
+pub fn serve(port: u16, dir: &str, json: bool) -> i32 {
     match crate::web::serve(port, dir) {
         Ok(()) => 0,
-        Err(e) => {
-            eprintln!("{e}");
-            1
-        }
+        Err(e) => fail(e, json),
     }
 }
@@ -0,0 +1,98 @@
+//! JSON rendering for `cf --json`.
+//!
+//! Every command prints exactly one JSON document on stdout, errors
+//! included, so callers can parse the output without checking stderr.
+
+use std::path::Path;
+
+use serde_json::{Value, json};
+
+use crate::core::message::Moderation;
+use crate::core::{Error, Message, thread};
+
+/// Prints `value` as a single line.
+pub fn print(value: &Value) {
+    println!("{value}");
+}
+
+/// `{"error": {...}}` with the stable exit code and kind of `e`.
+pub fn error(e: &Error) -> Value {
+    json!({
+        "error": {
+            "code": e.exit_code(),
+            "kind": e.kind(),
+            "message": e.to_string(),
+        }
+    })
+}
+
+pub fn path(path: &Path) -> Value {
+    Value::String(path.display().to_string())
+}
+
+pub fn paths<'a>(paths: impl IntoIterator<Item = &'a std::path::PathBuf>) -> Value {
+    paths.into_iter().map(|p| path(p)).collect()
+}
+
+/// Renders a Chatfile line as a parsed message, keeping the raw line.
+pub fn message(line: &str, id: Option<u64>) -> Value {
+    let msg = Message::parse(line);
+    let kind = match msg {
+        Message::Chat { .. } => "chat",
+        Message::Join { .. } => "join",
+        Message::Leave { .. } => "leave",
+        Message::Rename { .. } => "rename",
+        Message::Moderation { .. } => "moderation",
+        Message::System { .. } => "system",
+        Message::Admin { .. } => "admin",
+        Message::Other(_) => "other",
+    };
+    let name = match &msg {
+        Message::Chat { name, .. }
+        | Message::Join { name, .. }
+        | Message::Leave { name, .. }
+        | Message::Rename { from: name, .. }
+        | Message::Moderation { target: name, .. } => Some(name.as_str()),
+        Message::Admin { prefix, .. } => Some(prefix.as_str()),
+        _ => None,
+    };
+    let timestamp = match &msg {
+        Message::System { timestamp, .. } => Some(*timestamp),
+        _ => msg.timestamp(),
+    };
+
+    let mut value = json!({
+        "type": kind,
+        "name": name,
+        "text": msg.text(),
+        "timestamp": timestamp.map(|ts| ts.format("%F %T").to_string()),
+        "line": line,
+    });
+    match &msg {
+        Message::Chat { .. } => {
+            value["mentions"] = json!(msg.mentions());
+            value["reply_to"] = json!(thread::reply_to(&msg));
+        }
+        Message::Rename { to, .. } => value["to"] = json!(to),
+        Message::Moderation { action, by, .. } => {
+            value["action"] = json!(action.verb());
+            value["by"] = json!(by);
+            if let Moderation::Mute { until } = action {
+                value["until"] = json!(until.map(|ts| ts.format("%F %T").to_string()));
+            }
+        }
+        _ => {}
+    }
+    if let Some(id) = id {
+        value["id"] = json!(id);
+    }
+    value
+}
+
+pub fn messages(lines: &[String]) -> Value {
+    lines.iter().map(|l| message(l, None)).collect()
+}
+
+pub fn numbered(lines: &[(u64, String)]) -> Value {
+    lines.iter().map(|(id, l)| message(l, Some(*id))).collect()
+}
@@ -1,12 +1,35 @@
 pub mod commands;
+mod json;
+
+use std::path::PathBuf;
+use std::time::Duration;
 
 use clap::{Parser, Subcommand};
 
+use crate::core::acl::Role;
+use crate::core::duration;
+use crate::core::message::Moderation;
+use crate::core::ops::AwaitFilter;
+use crate::core::rate::RateLimit;
+use crate::core::{RoomOptions, SessionSelector};
+
 #[derive(Parser)]
 #[command(name = "cf")]
 #[command(about = "Chatfile tool for multi-agent coordination")]
 #[command(version)]
 pub struct Cli {
+    /// Print results and errors as JSON
+    #[arg(long, global = true)]
+    pub json: bool,
+
+    /// Use the session with this name or id instead of the default one
+    #[arg(long, global = true, value_name = "NAME|ID")]
+    pub session: Option<String>,
+
+    /// Use the session registered to this Chatfile
+    #[arg(long, global = true, value_name = "CHATFILE")]
+    pub room: Option<PathBuf>,
+
     #[command(subcommand)]
     pub command: Commands,
 }
@@ -18,6 +41,22 @@ pub enum Commands {
     CreateRoom {
         /// Room name (creates name.Chatfile, or Chatfile if omitted)
         name: Option<String>,
+
+        /// Prefix every chat, join and leave line with a timestamp
+        #[arg(short, long)]
+        timestamps: bool,
+
+        /// Limit every agent to N messages per window, e.g. 20/1m
+        #[arg(long, value_name = "N/WINDOW")]
+        rate: Option<RateLimit>,
+
+        /// Reject messages longer than this many characters
+        #[arg(long, value_name = "CHARS")]
+        max_length: Option<usize>,
+
+        /// Chain every line to the previous one by hash (see `cf verify`)
+        #[arg(long)]
+        hash_chain: bool,
     },
 
     /// List available rooms
@@ -47,25 +86,79 @@ pub enum Commands {
     /// Send a message
     #[command(visible_alias = "s")]
     Send {
-        /// Message to send
+        /// Message to send (`-` reads it from stdin)
         message: String,
+
+        /// Reply to the message with this id (see `cf read --ids`)
+        #[arg(short, long)]
+        reply_to: Option<u64>,
     },
 
     /// Send a message as admin
     #[command(visible_aliases = ["as", "admin"])]
     AdminSend {
-        /// Message to send
+        /// Message to send (`-` reads it from stdin)
         message: String,
     },
 
-    /// Wait for next message
+    /// Remove an agent from the room (admin); it may join again
+    Kick {
+        /// Agent name
+        name: String,
+    },
+
+    /// Stop an agent from sending (admin)
+    Mute {
+        /// Agent name
+        name: String,
+
+        /// Lift the mute after this long (e.g. 10m, 1h; default: never)
+        #[arg(long = "for", value_name = "DURATION", value_parser = duration::parse)]
+        duration: Option<Duration>,
+
+        /// Lift an earlier mute instead
+        #[arg(long, conflicts_with = "duration")]
+        lift: bool,
+    },
+
+    /// Remove an agent from the room and stop it from joining again (admin)
+    Ban {
+        /// Agent name
+        name: String,
+
+        /// Lift an earlier ban instead
+        #[arg(long)]
+        lift: bool,
+    },
+
+    /// Wait for new messages (prints all unseen ones)
     #[command(visible_aliases = ["a", "wait", "w"])]
-    Await,
+    Await {
+        /// Give up after this long (e.g. 30s, 5m, 1h); exits with code 124
+        #[arg(short, long, value_parser = duration::parse)]
+        timeout: Option<Duration>,
+
+        /// Return immediately, printing nothing if there are no new messages
+        #[arg(short, long, conflicts_with = "timeout")]
+        poll: bool,
+
+        /// Only wake for messages that @mention you (or @all)
+        #[arg(short, long)]
+        mentions: bool,
+
+        /// Only wake for messages from this sender
+        #[arg(short, long)]
+        from: Option<String>,
+
+        /// Wait on several rooms at once (comma-separated Chatfiles)
+        #[arg(short, long, value_delimiter = ',', conflicts_with = "poll")]
+        rooms: Vec<String>,
+    },
 
     /// Send and wait for reply
     #[command(visible_alias = "sa")]
     SendAwait {
-        /// Message to send
+        /// Message to send (`-` reads it from stdin)
         message: String,
     },
 
@@ -75,12 +168,87 @@ pub enum Commands {
         /// Number of messages to show
         #[arg(default_value = "20")]
         n: usize,
+
+        /// Show only lines appended since the last read, and mark them read
+        #[arg(long)]
+        new: bool,
+
+        /// Prefix each line with its message id
+        #[arg(short, long, conflicts_with = "new")]
+        ids: bool,
+
+        /// Show the reply thread containing this message id
+        #[arg(short, long, conflicts_with = "new")]
+        thread: Option<u64>,
     },
 
     /// Show current session
     #[command(visible_alias = "st")]
     Status,
 
+    /// List who is in the room and whether they are still alive
+    Who {
+        /// Chatfile to look at (default: the current session's room)
+        chatfile: Option<PathBuf>,
+    },
+
+    /// Check a room for replacement or truncation, and a hash-chained one
+    /// for edited, removed or reordered lines
+    Verify {
+        /// Chatfile to check (default: the current session's room)
+        chatfile: Option<PathBuf>,
+
+        /// Acknowledge a reported replacement or truncation and accept the
+        /// room as it is now
+        #[arg(long)]
+        accept: bool,
+    },
+
+    /// List who is in the room according to its join and leave lines
+    Members {
+        /// Chatfile to look at (default: the current session's room)
+        chatfile: Option<PathBuf>,
+    },
+
+    /// Tell the room you are still alive, e.g. during long work
+    #[command(visible_alias = "hb")]
+    Heartbeat,
+
+    /// Delete the current session, leaving the room first if joined
+    Unregister,
+
+    /// Change the current session's name (announced in the room if joined)
+    Rename {
+        /// New name
+        name: String,
+    },
+
+    /// List registered sessions; `*` marks the one commands use
+    Sessions {
+        #[command(subcommand)]
+        action: Option<SessionsAction>,
+    },
+
+    /// Pin a session to the current directory and its subdirectories
+    Use {
+        /// Session name or id (see `cf sessions`)
+        session: Option<String>,
+
+        /// Print an `export CF_SESSION=...` line for this shell instead
+        #[arg(long, conflicts_with = "unset")]
+        shell: bool,
+
+        /// Remove the pin on the current directory
+        #[arg(long)]
+        unset: bool,
+    },
+
+    /// List roles in the current room, or grant and revoke them
+    Role {
+        #[command(subcommand)]
+        action: Option<RoleAction>,
+    },
+
     /// Clear chatfiles and session data
     #[command(visible_aliases = ["cls", "clean"])]
     Clear {
@@ -91,6 +259,10 @@ pub enum Commands {
         /// Only delete session files, keep Chatfiles
         #[arg(short, long)]
         sessions_only: bool,
+
+        /// List the files that would be deleted, without deleting them
+        #[arg(short = 'n', long)]
+        dry_run: bool,
     },
 
     /// Start WebDAV server for remote access
@@ -106,29 +278,180 @@ pub enum Commands {
     },
 }
 
+#[derive(Subcommand)]
+pub enum RoleAction {
+    /// Give an agent a role: owner, moderator, member or observer
+    Grant {
+        /// Agent name
+        name: String,
+
+        role: Role,
+    },
+
+    /// Take an agent's role back, making it a member again
+    Revoke {
+        /// Agent name
+        name: String,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum SessionsAction {
+    /// Remove sessions whose Chatfile is gone, or that have been idle too long
+    Gc {
+        /// Also remove sessions unused for longer than this (e.g. 12h, 7d)
+        #[arg(long, value_parser = duration::parse)]
+        idle: Option<Duration>,
+
+        /// List what would be removed, without removing it
+        #[arg(short = 'n', long)]
+        dry_run: bool,
+    },
+}
+
 pub fn run() -> i32 {
     crate::log::init();
     crate::log::debug("CLI", "Starting cf command");
 
-    let cli = Cli::parse();
+    let cli = match Cli::try_parse() {
+        Ok(cli) => cli,
+        Err(e) => return usage_error(e),
+    };
+    let json = cli.json;
+    let selector = SessionSelector {
+        session: cli.session.clone(),
+        room: cli.room.clone(),
+    };
 
     match cli.command {
-        Commands::CreateRoom { name } => commands::create_room(name.as_deref()),
-        Commands::ListRooms => commands::list_rooms(),
-        Commands::Register { chatfile, name } => commands::register(&chatfile, name.as_deref()),
-        Commands::Join => commands::join(),
-        Commands::Leave => commands::leave(),
-        Commands::Send { message } => commands::send(&message),
-        Commands::AdminSend { message } => commands::admin_send(&message),
-        Commands::Await => commands::await_message(),
-        Commands::SendAwait { message } => commands::send_await(&message),
-        Commands::Read { n } => commands::read(n),
-        Commands::Status => commands::status(),
+        Commands::CreateRoom {
+            name,
+            timestamps,
+            rate,
+            max_length,
+            hash_chain,
+        } => {
+            let options = RoomOptions {
+                timestamps,
+                rate_limit: rate,
+                max_length,
+                hash_chain,
+            };
+            commands::create_room(name.as_deref(), options, json)
+        }
+        Commands::ListRooms => commands::list_rooms(json),
+        Commands::Register { chatfile, name } => {
+            commands::register(&chatfile, name.as_deref(), json)
+        }
+        Commands::Join => commands::join(&selector, json),
+        Commands::Leave => commands::leave(&selector, json),
+        Commands::Send { message, reply_to } => commands::send(&selector, &message, reply_to, json),
+        Commands::AdminSend { message } => commands::admin_send(&selector, &message, json),
+        Commands::Kick { name } => commands::moderate(&selector, &name, Moderation::Kick, json),
+        Commands::Mute {
+            name, lift: true, ..
+        } => commands::moderate(&selector, &name, Moderation::Unmute, json),
+        Commands::Mute { name, duration, .. } => commands::mute(&selector, &name, duration, json),
+        Commands::Ban { name, lift } => {
+            let action = if lift {
+                Moderation::Unban
+            } else {
+                Moderation::Ban
+            };
+            commands::moderate(&selector, &name, action, json)
+        }
+        Commands::Await {
+            timeout,
+            poll,
+            mentions,
+            from,
+            rooms,
+        } => {
+            let filter = AwaitFilter { mentions, from };
+            if rooms.is_empty() {
+                commands::await_message(&selector, &filter, timeout, poll, json)
+            } else {
+                commands::await_rooms(&selector, &rooms, &filter, timeout, json)
+            }
+        }
+        Commands::SendAwait { message } => commands::send_await(&selector, &message, json),
+        Commands::Read {
+            n,
+            new,
+            ids,
+            thread,
+        } => match thread {
+            Some(id) => commands::read_thread(&selector, id, json),
+            None => commands::read(&selector, n, new, ids, json),
+        },
+        Commands::Status => commands::status(&selector, json),
+        Commands::Who { chatfile } => commands::who(&selector, chatfile.as_deref(), json),
+        Commands::Verify { chatfile, accept } => {
+            commands::verify(&selector, chatfile.as_deref(), accept, json)
+        }
+        Commands::Members { chatfile } => commands::members(&selector, chatfile.as_deref(), json),
+        Commands::Heartbeat => commands::heartbeat(&selector, json),
+        Commands::Unregister => commands::unregister(&selector, json),
+        Commands::Rename { name } => commands::rename(&selector, &name, json),
+        Commands::Role { action: None } => commands::roles(&selector, json),
+        Commands::Role {
+            action: Some(RoleAction::Grant { name, role }),
+        } => commands::grant_role(&selector, &name, role, json),
+        Commands::Role {
+            action: Some(RoleAction::Revoke { name }),
+        } => commands::revoke_role(&selector, &name, json),
+        Commands::Sessions { action: None } => commands::sessions(&selector, json),
+        Commands::Sessions {
+            action: Some(SessionsAction::Gc { idle, dry_run }),
+        } => commands::gc_sessions(idle, dry_run, json),
+        Commands::Use {
+            session,
+            shell,
+            unset,
+        } => {
+            let selector = SessionSelector {
+                session: session.or(cli.session),
+                room: cli.room,
+            };
+            commands::use_session(&selector, shell, unset, json)
+        }
         Commands::Clear {
             force,
             sessions_only,
-        } => commands::clear(force, sessions_only),
+            dry_run,
+        } => commands::clear(force, sessions_only, dry_run, json),
         #[cfg(feature = "web")]
-        Commands::Serve { port, dir } => commands::serve(port, &dir),
+        Commands::Serve { port, dir } => commands::serve(port, &dir, json),
+    }
+}
+
+/// Reports a command line parse failure. `--json` is looked up in the raw
+/// arguments, since parsing is what failed.
+fn usage_error(e: clap::Error) -> i32 {
+    use clap::error::ErrorKind;
+
+    let informational = matches!(e.kind(), ErrorKind::DisplayHelp | ErrorKind::DisplayVersion);
+    if informational || !wants_json(std::env::args().skip(1)) {
+        e.exit();
     }
+
+    let message = e.kind().as_str().unwrap_or("invalid arguments");
+    json::print(&serde_json::json!({
+        "error": { "code": e.exit_code(), "kind": "usage", "message": message }
+    }));
+    e.exit_code()
+}
+
+/// Whether `args` ask for `--json`. Arguments after `--` are values, such
+/// as a message, and `--json=<bool>` is honoured although clap rejects it.
+fn wants_json(args: impl IntoIterator<Item = String>) -> bool {
+    args.into_iter()
+        .take_while(|a| a != "--")
+        .any(|a| match a.strip_prefix("--json") {
+            Some("") => true,
+            Some(value) => value
+                .strip_prefix('=')
+                .is_some_and(|v| !matches!(v, "false" | "0" | "no" | "off")),
+            None => false,
+        })
 }
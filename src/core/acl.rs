@@ -0,0 +1,182 @@
+//! Per-room roles.
+//!
+//! A room without an ACL is open: everyone may join and send, and admin
+//! messages need a `.cf_admin` file. `cf role grant` creates an ACL file
+//! next to the room, `.<chatfile name>.acl`, with one `<role>=<name>` line
+//! per agent. From then on the session's role decides instead. Names not
+//! listed are members.
+//!
+//! Roles bind names, and any agent can register under any free name, so
+//! the ACL keeps cooperating agents in their lanes; it is not a security
+//! boundary. File permissions are.
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use super::error::Result;
+use super::sidecar;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Role {
+    /// May join to read, but never writes to the room: its join and leave
+    /// are not announced and it can't send
+    Observer,
+    /// May join and send
+    Member,
+    /// May also send admin messages
+    Moderator,
+    /// May also grant and revoke roles
+    Owner,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Observer => "observer",
+            Self::Member => "member",
+            Self::Moderator => "moderator",
+            Self::Owner => "owner",
+        }
+    }
+
+    pub fn can_send(&self) -> bool {
+        *self >= Self::Member
+    }
+
+    pub fn can_admin_send(&self) -> bool {
+        *self >= Self::Moderator
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Role {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "observer" => Ok(Self::Observer),
+            "member" => Ok(Self::Member),
+            "moderator" => Ok(Self::Moderator),
+            "owner" => Ok(Self::Owner),
+            _ => Err(format!(
+                "unknown role '{s}' (expected owner, moderator, member or observer)"
+            )),
+        }
+    }
+}
+
+/// Returns the ACL file of `chatfile`.
+pub fn path(chatfile: &Path) -> PathBuf {
+    sidecar::path(chatfile, "acl")
+}
+
+/// The roles granted in a room, in the order they were granted.
+#[derive(Debug, Clone, Default)]
+pub struct Acl {
+    entries: Vec<(String, Role)>,
+}
+
+impl Acl {
+    /// Loads the ACL of `chatfile`, or `None` if the room has none.
+    /// Unreadable lines are skipped.
+    pub fn load(chatfile: &Path) -> Result<Option<Self>> {
+        let content = match std::fs::read_to_string(path(chatfile)) {
+            Ok(c) => c,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+
+        let entries = content
+            .lines()
+            .filter(|line| !line.starts_with('#'))
+            .filter_map(|line| {
+                let (role, name) = line.split_once('=')?;
+                Some((name.to_string(), role.parse().ok()?))
+            })
+            .collect();
+        Ok(Some(Self { entries }))
+    }
+
+    /// Writes the ACL of `chatfile`, or removes it once no roles are left,
+    /// which opens the room again.
+    pub fn save(&self, chatfile: &Path) -> Result<()> {
+        let path = path(chatfile);
+        if self.entries.is_empty() {
+            return match std::fs::remove_file(&path) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+                _ => Ok(()),
+            };
+        }
+
+        let content: String = self
+            .entries
+            .iter()
+            .map(|(name, role)| format!("{role}={name}\n"))
+            .collect();
+        sidecar::write_atomic(&path, &content)
+    }
+
+    pub fn entries(&self) -> &[(String, Role)] {
+        &self.entries
+    }
+
+    /// Returns the role of `name`; unlisted names are members.
+    pub fn role(&self, name: &str) -> Role {
+        self.entries
+            .iter()
+            .find(|(n, _)| n == name)
+            .map_or(Role::Member, |(_, role)| *role)
+    }
+
+    /// Whether `name` has a role of its own.
+    pub fn lists(&self, name: &str) -> bool {
+        self.entries.iter().any(|(n, _)| n == name)
+    }
+
+    pub fn has_owner(&self) -> bool {
+        self.entries.iter().any(|(_, role)| *role == Role::Owner)
+    }
+
+    pub fn grant(&mut self, name: &str, role: Role) {
+        match self.entries.iter_mut().find(|(n, _)| n == name) {
+            Some(entry) => entry.1 = role,
+            None => self.entries.push((name.to_string(), role)),
+        }
+    }
+
+    /// Removes the role of `name`. Returns whether it had one.
+    pub fn revoke(&mut self, name: &str) -> bool {
+        let before = self.entries.len();
+        self.entries.retain(|(n, _)| n != name);
+        self.entries.len() != before
+    }
+
+    /// Moves the role of `from` to `to`, replacing any role `to` had, so
+    /// `to` ends up with exactly the role `from` had. Returns whether that
+    /// changed anything.
+    pub fn rename(&mut self, from: &str, to: &str) -> bool {
+        let before = self.entries.len();
+        self.entries.retain(|(n, _)| n != to);
+        let replaced = self.entries.len() != before;
+
+        match self.entries.iter_mut().find(|(n, _)| n == from) {
+            Some(entry) => {
+                entry.0 = to.to_string();
+                true
+            }
+            None => replaced,
+        }
+    }
+}
+
+/// Returns the role of `name` in `chatfile`, or `None` if the room has no
+/// ACL.
+pub fn role_of(chatfile: &Path, name: &str) -> Result<Option<Role>> {
+    Ok(Acl::load(chatfile)?.map(|acl| acl.role(name)))
+}
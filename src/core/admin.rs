@@ -1,7 +1,9 @@
 use std::path::PathBuf;
 
 use super::chatfile::Chatfile;
+use super::dirs;
 use super::error::{Error, Result};
+use super::message::Message;
 
 const ADMIN_FILE: &str = ".cf_admin";
 const DEFAULT_ADMIN_PREFIX: &str = "admin";
@@ -47,14 +49,21 @@ pub fn verify_admin() -> Result<AdminConfig> {
 }
 
 pub fn admin_send(chatfile_path: &str, message: &str) -> Result<()> {
-    let config = verify_admin()?;
+    send_as(&verify_admin()?, chatfile_path, message)
+}
 
+/// Writes an admin message without checking for `.cf_admin`, for callers
+/// that authorized it some other way (room roles).
+pub fn send_as(config: &AdminConfig, chatfile_path: &str, message: &str) -> Result<()> {
     if message.is_empty() {
         return Err(Error::EmptyMessage);
     }
 
     let chatfile = Chatfile::open(chatfile_path)?;
-    chatfile.append(&format!("[{}]: {}", config.prefix, message))
+    chatfile.append_message(&Message::Admin {
+        prefix: config.prefix.clone(),
+        text: message.to_string(),
+    })
 }
 
 fn find_admin_file() -> Result<PathBuf> {
@@ -65,8 +74,8 @@ fn find_admin_file() -> Result<PathBuf> {
     }
 
     // Check XDG config directory
-    if let Some(proj_dirs) = directories::ProjectDirs::from("", "", "chatfiles") {
-        let xdg_admin = proj_dirs.config_dir().join(ADMIN_FILE);
+    if let Ok(config_dir) = dirs::config_dir() {
+        let xdg_admin = config_dir.join(ADMIN_FILE);
         if xdg_admin.exists() {
             return Ok(xdg_admin);
         }
@@ -0,0 +1,129 @@
+//! Hash chain over the lines of a room, for rooms created with
+//! `--hash-chain`.
+//!
+//! Every line after the header starts with a `[#xxxxxxxx] ` tag: the first
+//! 8 hex digits of SHA-256 over the previous line's chain value and the
+//! rest of the line. A line's chain value is its tag, or for untagged lines
+//! like the header, the digest of the whole line. Editing, removing or
+//! moving a line breaks the chain at that line or the next, which
+//! `cf verify` reports.
+//!
+//! The hash has no key, so someone determined can rewrite the whole tail
+//! of the chain. It catches careless and casual edits, not attackers, and
+//! can't tell a truncated room from a shorter one.
+
+use std::fmt;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+
+use sha2::{Digest, Sha256};
+
+use super::error::Result;
+
+/// Hex digits in a tag.
+const TAG_LEN: usize = 8;
+
+/// Splits off a leading `[#xxxxxxxx] ` tag, if present.
+pub fn split(line: &str) -> (Option<&str>, &str) {
+    let tagged = line.strip_prefix("[#").and_then(|rest| {
+        let (tag, rest) = rest.split_at_checked(TAG_LEN)?;
+        let rest = rest.strip_prefix("] ")?;
+        tag.bytes()
+            .all(|b| b.is_ascii_hexdigit())
+            .then_some((tag, rest))
+    });
+
+    match tagged {
+        Some((tag, rest)) => (Some(tag), rest),
+        None => (None, line),
+    }
+}
+
+fn digest(parts: &[&str]) -> String {
+    let mut hasher = Sha256::new();
+    for part in parts {
+        hasher.update(part.as_bytes());
+    }
+    let hash = hasher.finalize();
+    hash.iter()
+        .take(TAG_LEN / 2)
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+/// Returns the chain value of `line`: its tag, or the digest of an
+/// untagged line.
+fn value(line: &str) -> String {
+    match split(line) {
+        (Some(tag), _) => tag.to_string(),
+        (None, _) => digest(&[line]),
+    }
+}
+
+/// Returns `content` tagged to follow `prev`, the last line in the room.
+pub fn link(prev: &str, content: &str) -> String {
+    let tag = digest(&[&value(prev), "\n", content]);
+    format!("[#{tag}] {content}")
+}
+
+/// Where a chain first fails to hold. Line numbers are message ids.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Break {
+    /// The line carries no tag, e.g. appended by a tool that doesn't know
+    /// about the chain
+    Untagged(u64),
+    /// The tag doesn't match: this line was changed, or the line before it
+    /// was changed, removed or moved
+    Mismatch(u64),
+}
+
+impl Break {
+    pub fn line(&self) -> u64 {
+        match self {
+            Self::Untagged(line) | Self::Mismatch(line) => *line,
+        }
+    }
+}
+
+impl fmt::Display for Break {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Untagged(line) => write!(f, "line {line} is not chained"),
+            Self::Mismatch(line) => write!(
+                f,
+                "line {line} does not match the chain: it, or the line before it, was changed, removed or moved"
+            ),
+        }
+    }
+}
+
+/// Walks the chain of the room at `path`. Returns the number of lines
+/// checked, and the first break if there is one.
+pub fn verify(path: &Path) -> Result<(u64, Option<Break>)> {
+    let mut reader = BufReader::new(File::open(path)?);
+
+    let mut prev: Option<String> = None;
+    let mut buf = Vec::new();
+    let mut id = 0;
+    loop {
+        buf.clear();
+        if reader.read_until(b'\n', &mut buf)? == 0 {
+            return Ok((id, None));
+        }
+        id += 1;
+        let line = String::from_utf8_lossy(&buf);
+        let line = line.trim_end_matches('\n');
+
+        if let Some(prev) = &prev {
+            match split(line) {
+                (None, _) => return Ok((id, Some(Break::Untagged(id)))),
+                (Some(_), content) if link(prev, content) != line => {
+                    return Ok((id, Some(Break::Mismatch(id))));
+                }
+                _ => {}
+            }
+        }
+        prev = Some(line.to_string());
+    }
+}
@@ -1,18 +1,33 @@
 use std::fs::{File, OpenOptions};
-use std::io::{BufRead, BufReader, Write};
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
 use std::sync::mpsc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use chrono::Local;
+use chrono::{Local, NaiveDateTime, Timelike};
 use notify::{Event, RecursiveMode, Watcher};
 
+use super::chain;
 use super::error::{Error, Result};
+use super::lines::LineCount;
+use super::lock::FileLock;
+use super::message::{self, Message, Moderation};
+use super::moderation::Standings;
+use super::protect;
+use super::rate;
+use super::room::{RoomOptions, RosterEntry, Standing};
+use super::thread;
+use super::watch::WatchMode;
 use crate::log;
 
-#[derive(Debug)]
+/// Block size used when scanning a Chatfile backwards from the end.
+const TAIL_CHUNK: usize = 8 * 1024;
+
+#[derive(Debug, Clone)]
 pub struct Chatfile {
     pub path: PathBuf,
+    /// Settings recorded in the room header
+    pub options: RoomOptions,
 }
 
 impl Chatfile {
@@ -22,11 +37,29 @@ impl Chatfile {
             log::warn("Chatfile", &format!("File not found: {}", path.display()));
             return Err(Error::ChatfileNotFound(path));
         }
+        // Rewrites are recorded, not raised; see `protect::observe`
+        protect::observe(&path);
         log::debug("Chatfile", &format!("Opened: {}", path.display()));
-        Ok(Self { path })
+        let options = Self::read_options(&path)?;
+        Ok(Self { path, options })
+    }
+
+    /// Reads the room options from the header line.
+    fn read_options(path: &Path) -> Result<RoomOptions> {
+        let mut header = String::new();
+        BufReader::new(File::open(path)?).read_line(&mut header)?;
+
+        Ok(match Message::parse(header.trim_end()) {
+            Message::System { text, .. } => RoomOptions::from_header(&text),
+            _ => RoomOptions::default(),
+        })
     }
 
     pub fn create(name: Option<&str>) -> Result<Self> {
+        Self::create_with(name, RoomOptions::default())
+    }
+
+    pub fn create_with(name: Option<&str>, options: RoomOptions) -> Result<Self> {
         let filename = match name {
             Some(n) => format!("{n}.Chatfile"),
             None => "Chatfile".to_string(),
@@ -42,29 +75,23 @@ impl Chatfile {
         }
 
         let room_name = name.unwrap_or("default");
-        let timestamp = Local::now().format("%F %T");
-        let header = format!(
-            "[system {timestamp}]: Chatroom \"{room_name}\". Format: Name: msg. Append only.\n"
-        );
+        let mut text = format!("Chatroom \"{room_name}\". Format: Name: msg. Append only.");
+        if !options.is_default() {
+            text.push_str(&format!(" {options}"));
+        }
+        let header = Message::System {
+            timestamp: now(),
+            text,
+        };
 
-        std::fs::write(&path, header)?;
-        Self::try_set_append_only(&path);
+        std::fs::write(&path, format!("{header}\n"))?;
+        let protection = protect::protect(&path);
+        log::debug("Chatfile", &format!("Protection: {protection}"));
 
         log::info("Chatfile", &format!("Created room: {}", path.display()));
-        Ok(Self { path })
+        Ok(Self { path, options })
     }
 
-    #[cfg(target_os = "linux")]
-    fn try_set_append_only(path: &Path) {
-        let _ = std::process::Command::new("sudo")
-            .args(["chattr", "+a"])
-            .arg(path)
-            .status();
-    }
-
-    #[cfg(not(target_os = "linux"))]
-    fn try_set_append_only(_path: &Path) {}
-
     pub fn list_rooms() -> Result<Vec<PathBuf>> {
         let mut rooms = Vec::new();
 
@@ -82,36 +109,253 @@ impl Chatfile {
         Ok(rooms)
     }
 
+    /// Appends `content` as one line, written on an O_APPEND descriptor
+    /// while holding `lock`. POSIX doesn't promise that a write to a
+    /// regular file lands in one piece, and `write_all` may need several
+    /// writes anyway, so every append is serialized rather than trusting
+    /// the kernel with small ones. In hash-chained rooms the lock also
+    /// covers reading the line the new one links to. Afterwards the
+    /// snapshot is updated, which records the room as tampered with if it
+    /// was replaced or truncated since the last append; see
+    /// `protect::observe`.
     pub fn append(&self, content: &str) -> Result<()> {
         let mut file = OpenOptions::new().append(true).open(&self.path)?;
-        writeln!(file, "{content}")?;
+        let lock = self.lock()?;
+
+        let mut line = if self.options.hash_chain {
+            let prev = self.read_last(1)?.pop().unwrap_or_default();
+            chain::link(&prev, content)
+        } else {
+            content.to_string()
+        };
+        line.push('\n');
+
+        file.write_all(line.as_bytes())?;
+        drop(lock);
+        protect::observe(&self.path);
         Ok(())
     }
 
+    /// Takes an exclusive advisory lock on the Chatfile, held until the
+    /// returned guard is dropped.
+    pub fn lock(&self) -> Result<FileLock> {
+        FileLock::exclusive(File::open(&self.path)?)
+    }
+
     pub fn send(&self, name: &str, message: &str) -> Result<()> {
+        self.send_text(name, message, message)
+    }
+
+    /// Sends `message` as a reply to the message with id `reply_to`.
+    pub fn send_reply(&self, name: &str, reply_to: u64, message: &str) -> Result<()> {
+        if message.is_empty() {
+            return Err(Error::EmptyMessage);
+        }
+        if reply_to == 0 || reply_to > self.line_count()? {
+            return Err(Error::UnknownMessage(reply_to));
+        }
+        self.send_text(name, &thread::reply_text(reply_to, message), message)
+    }
+
+    /// Writes `text` as a chat line from `name`. The room's `max-length`
+    /// and `rate` options apply to `message`, what the agent wrote, and a
+    /// send only counts against the rate limit once it is in the room.
+    fn send_text(&self, name: &str, text: &str, message: &str) -> Result<()> {
         if message.is_empty() {
             log::warn("Chatfile", "Attempted to send empty message");
             return Err(Error::EmptyMessage);
         }
+        if let Some(max) = self.options.max_length {
+            let len = message.chars().count();
+            if len > max {
+                return Err(Error::MessageTooLong(len, max));
+            }
+        }
+        let recorded = match self.options.rate_limit {
+            Some(limit) => Some(rate::record(&self.path, name, limit)?),
+            None => None,
+        };
+
         log::debug("Chatfile", &format!("{name} sending message"));
-        self.append(&format!("{name}: {message}"))
+        let sent = self.append_message(&Message::Chat {
+            name: name.to_string(),
+            text: text.to_string(),
+            timestamp: self.stamp(),
+        });
+        if let (Err(_), Some(at)) = (&sent, recorded) {
+            if let Err(e) = rate::forget(&self.path, name, at) {
+                log::warn("Chatfile", &format!("Could not take back the send: {e}"));
+            }
+        }
+        sent
+    }
+
+    /// Current time for chat, join and leave lines, if the room stamps them.
+    fn stamp(&self) -> Option<NaiveDateTime> {
+        self.options.timestamps.then(now)
+    }
+
+    pub fn append_message(&self, message: &Message) -> Result<()> {
+        self.append(&message.to_string())
     }
 
     pub fn announce_join(&self, name: &str) -> Result<()> {
-        self.append(&format!("[{name} joined]"))
+        self.append_message(&Message::Join {
+            name: name.to_string(),
+            timestamp: self.stamp(),
+        })
     }
 
     pub fn announce_leave(&self, name: &str) -> Result<()> {
-        self.append(&format!("[{name} left]"))
+        self.append_message(&Message::Leave {
+            name: name.to_string(),
+            timestamp: self.stamp(),
+        })
+    }
+
+    pub fn announce_rename(&self, from: &str, to: &str) -> Result<()> {
+        self.append_message(&Message::Rename {
+            from: from.to_string(),
+            to: to.to_string(),
+            timestamp: self.stamp(),
+        })
     }
 
+    pub fn announce_moderation(&self, target: &str, action: Moderation, by: &str) -> Result<()> {
+        self.append_message(&Message::Moderation {
+            target: target.to_string(),
+            action,
+            by: by.to_string(),
+            timestamp: self.stamp(),
+        })
+    }
+
+    /// Returns the last `n` lines. Seeks backwards from the end of the file,
+    /// so the cost depends on the size of the returned lines, not the file.
     pub fn read_last(&self, n: usize) -> Result<Vec<String>> {
-        let file = File::open(&self.path)?;
+        let mut file = File::open(&self.path)?;
+        let start = Self::tail_offset(&mut file, n)?;
+
+        file.seek(SeekFrom::Start(start))?;
         let reader = BufReader::new(file);
-        let lines: Vec<String> = reader.lines().collect::<std::io::Result<_>>()?;
+        Ok(reader.lines().collect::<std::io::Result<_>>()?)
+    }
+
+    /// Like `read_last`, but pairs each line with its message id.
+    /// Numbering the lines needs a count of everything before them.
+    pub fn read_last_numbered(&self, n: usize) -> Result<Vec<(u64, String)>> {
+        let mut file = File::open(&self.path)?;
+        let start = Self::tail_offset(&mut file, n)?;
+        let first = self.newlines_before(&mut file, start)? + 1;
+
+        file.seek(SeekFrom::Start(start))?;
+        let reader = BufReader::new(file);
+        reader
+            .lines()
+            .zip(first..)
+            .map(|(line, id)| Ok((id, line?)))
+            .collect()
+    }
+
+    /// Returns the number of lines, which is also the id of the last one.
+    pub fn line_count(&self) -> Result<u64> {
+        let mut file = File::open(&self.path)?;
+        let len = file.metadata()?.len();
+        let newlines = self.newlines_before(&mut file, len)?;
 
-        let start = lines.len().saturating_sub(n);
-        Ok(lines[start..].to_vec())
+        // A trailing line without newline still counts
+        let mut last = [0u8; 1];
+        if len > 0 {
+            file.seek(SeekFrom::Start(len - 1))?;
+            file.read_exact(&mut last)?;
+        }
+        Ok(if len > 0 && last[0] != b'\n' {
+            newlines + 1
+        } else {
+            newlines
+        })
+    }
+
+    /// Counts the newlines in the first `end` bytes of `file`, starting
+    /// from the room's line count cache rather than the top of the file,
+    /// and moving the cache forward.
+    fn newlines_before(&self, file: &mut File, end: u64) -> Result<u64> {
+        let inode = self.inode()?.unwrap_or(0);
+        let size = file.metadata()?.len();
+
+        // A replaced or truncated room starts over
+        let cached = LineCount::load(&self.path)
+            .filter(|c| c.inode == inode && c.offset <= size)
+            .unwrap_or(LineCount {
+                inode,
+                ..LineCount::default()
+            });
+
+        if end < cached.offset {
+            let between = Self::count_newlines(file, end, cached.offset)?;
+            return Ok(cached.lines.saturating_sub(between));
+        }
+        let count = LineCount {
+            offset: end,
+            inode,
+            lines: cached.lines + Self::count_newlines(file, cached.offset, end)?,
+        };
+        if count != cached {
+            if let Err(e) = count.save(&self.path) {
+                log::warn("Chatfile", &format!("Could not cache the line count: {e}"));
+            }
+        }
+        Ok(count.lines)
+    }
+
+    /// Counts the newlines between byte offsets `start` and `end` of `file`.
+    fn count_newlines(file: &mut File, start: u64, end: u64) -> Result<u64> {
+        file.seek(SeekFrom::Start(start))?;
+        let mut reader = file.take(end - start);
+
+        let mut buf = vec![0u8; 64 * 1024];
+        let mut count = 0;
+        loop {
+            let read = reader.read(&mut buf)?;
+            if read == 0 {
+                return Ok(count);
+            }
+            count += buf[..read].iter().filter(|&&b| b == b'\n').count() as u64;
+        }
+    }
+
+    /// Finds the byte offset where the last `n` lines begin.
+    fn tail_offset(file: &mut File, n: usize) -> Result<u64> {
+        let len = file.metadata()?.len();
+        if n == 0 {
+            return Ok(len);
+        }
+
+        let mut buf = [0u8; TAIL_CHUNK];
+        let mut pos = len;
+        let mut found = 0;
+
+        while pos > 0 {
+            let chunk = pos.min(TAIL_CHUNK as u64);
+            pos -= chunk;
+            let chunk = &mut buf[..chunk as usize];
+
+            file.seek(SeekFrom::Start(pos))?;
+            file.read_exact(chunk)?;
+
+            for (i, &byte) in chunk.iter().enumerate().rev() {
+                let at = pos + i as u64;
+                // The final newline terminates the last line, it doesn't start one
+                if byte == b'\n' && at + 1 != len {
+                    found += 1;
+                    if found == n {
+                        return Ok(at + 1);
+                    }
+                }
+            }
+        }
+
+        Ok(0)
     }
 
     pub fn last_line(&self) -> Result<Option<String>> {
@@ -119,73 +363,232 @@ impl Chatfile {
         Ok(lines.into_iter().next())
     }
 
-    /// Extracts sender name from a message line.
+    /// Extracts sender name from a message line, with or without timestamp.
     /// Returns None for system messages (starting with `[`) or lines without sender.
     pub fn get_sender(line: &str) -> Option<&str> {
-        // System messages like [user joined] or [system ...] are not from users
-        if line.starts_with('[') {
-            return None;
+        let (_, line) = message::split_timestamp(line);
+        message::split_chat(line).map(|(name, _)| name)
+    }
+
+    /// Extracts the names @mentioned in a chat line, looking at the text
+    /// with its line breaks unescaped. Returns an empty list for system
+    /// messages.
+    pub fn get_mentions(line: &str) -> Vec<String> {
+        Message::parse(line)
+            .mentions()
+            .into_iter()
+            .map(str::to_string)
+            .collect()
+    }
+
+    pub fn read_messages(&self, n: usize) -> Result<Vec<Message>> {
+        let lines = self.read_last(n)?;
+        Ok(lines.iter().map(|l| Message::parse(l)).collect())
+    }
+
+    /// Calls `f` with the parsed record of every complete bracketed line
+    /// from byte `offset` on, i.e. everything but chat messages, which are
+    /// skipped unparsed. Lines are numbered from `offset`, so from 0 the
+    /// numbers are message ids. Returns the offset just past the last
+    /// complete line.
+    fn for_each_record(&self, offset: u64, mut f: impl FnMut(u64, Message)) -> Result<u64> {
+        let mut file = File::open(&self.path)?;
+        file.seek(SeekFrom::Start(offset))?;
+        let mut reader = BufReader::with_capacity(64 * 1024, file);
+
+        let mut buf = Vec::new();
+        let mut next = offset;
+        let mut id = 0;
+        loop {
+            buf.clear();
+            let read = reader.read_until(b'\n', &mut buf)?;
+            if read == 0 || buf.last() != Some(&b'\n') {
+                return Ok(next);
+            }
+            next += read as u64;
+            id += 1;
+
+            let line = String::from_utf8_lossy(&buf);
+            let line = line.trim_end_matches('\n');
+            if message::split_timestamp(line).1.starts_with('[') {
+                f(id, Message::parse(line));
+            }
         }
-        // Must have format "name: message"
-        let colon_pos = line.find(':')?;
-        if colon_pos == 0 {
-            return None;
+    }
+
+    /// Replays the join, leave, rename and moderation lines into the
+    /// current roster, in the order members joined. Joining again moves a
+    /// member to the end; kicks and bans remove it like a leave.
+    pub fn members(&self) -> Result<Vec<RosterEntry>> {
+        let mut roster: Vec<RosterEntry> = Vec::new();
+        self.for_each_record(0, |id, msg| match msg {
+            Message::Join { name, timestamp } => {
+                roster.retain(|m| m.name != name);
+                roster.push(RosterEntry {
+                    name,
+                    joined_at: timestamp,
+                    joined_id: id,
+                });
+            }
+            Message::Leave { name, .. }
+            | Message::Moderation {
+                target: name,
+                action: Moderation::Kick | Moderation::Ban,
+                ..
+            } => roster.retain(|m| m.name != name),
+            Message::Rename { from, to, .. } => {
+                if let Some(member) = roster.iter_mut().find(|m| m.name == from) {
+                    member.name = to;
+                }
+            }
+            _ => {}
+        })?;
+        Ok(roster)
+    }
+
+    /// Returns the moderation in effect for `name`, replaying only the
+    /// lines appended since the room's standings cache was last updated.
+    pub fn standing(&self, name: &str) -> Result<Standing> {
+        let inode = self.inode()?.unwrap_or(0);
+        let size = self.size()?;
+
+        // A replaced or truncated room starts over
+        let mut standings = Standings::load(&self.path)
+            .filter(|s| s.inode == inode && s.offset <= size)
+            .unwrap_or_default();
+        standings.inode = inode;
+
+        let start = standings.offset;
+        let end = self.for_each_record(start, |_, msg| standings.apply(msg))?;
+        if end != start {
+            standings.offset = end;
+            if let Err(e) = standings.save(&self.path) {
+                log::warn("Chatfile", &format!("Could not cache standings: {e}"));
+            }
         }
-        Some(&line[..colon_pos])
+        Ok(standings.get(name))
     }
 
+    /// Checks whether `name` has ever sent a message in this room.
+    /// This has to scan the whole file, but works on raw bytes with a
+    /// single reused buffer instead of allocating a `String` per line.
     pub fn name_exists(&self, name: &str) -> Result<bool> {
         let file = File::open(&self.path)?;
-        let reader = BufReader::new(file);
+        let mut reader = BufReader::with_capacity(64 * 1024, file);
         let prefix = format!("{name}:");
 
-        for line in reader.lines() {
-            if line?.starts_with(&prefix) {
+        let mut buf = Vec::new();
+        loop {
+            buf.clear();
+            if reader.read_until(b'\n', &mut buf)? == 0 {
+                return Ok(false);
+            }
+            // Skip a `[#xxxxxxxx] ` chain tag and a `[YYYY-MM-DD HH:MM:SS] `
+            // stamp, by their shape
+            let mut line = &buf[..];
+            if line.starts_with(b"[#") && line.get(10..12) == Some(b"] ") {
+                line = &line[12..];
+            }
+            if line.starts_with(b"[") && line.get(20..22) == Some(b"] ") {
+                line = &line[22..];
+            }
+            if line.starts_with(prefix.as_bytes()) {
                 return Ok(true);
             }
         }
-        Ok(false)
     }
 
-    pub fn watch(&self) -> Result<String> {
-        // Get initial line count
-        let initial_lines = self.count_lines()?;
+    /// Returns the current size of the Chatfile in bytes.
+    pub fn size(&self) -> Result<u64> {
+        Ok(std::fs::metadata(&self.path)?.len())
+    }
 
-        // Set up file watcher with channel
-        let (tx, rx) = mpsc::channel();
-        let mut watcher = notify::recommended_watcher(move |res: std::result::Result<Event, _>| {
-            if let Ok(event) = res {
-                if event.kind.is_modify() {
-                    let _ = tx.send(());
-                }
+    /// Reads every complete line appended at or after byte `offset`.
+    /// Returns the lines and the offset just past the last complete line,
+    /// so a partially written trailing line is picked up on the next call.
+    pub fn read_from(&self, offset: u64) -> Result<(Vec<String>, u64)> {
+        let mut file = File::open(&self.path)?;
+        file.seek(SeekFrom::Start(offset))?;
+        let mut reader = BufReader::new(file);
+
+        let mut lines = Vec::new();
+        let mut next = offset;
+        let mut buf = Vec::new();
+        loop {
+            buf.clear();
+            let read = reader.read_until(b'\n', &mut buf)?;
+            if read == 0 || buf.last() != Some(&b'\n') {
+                break;
             }
-        })
-        .map_err(|e| Error::Io(std::io::Error::other(format!("watcher error: {e}"))))?;
+            next += read as u64;
+            buf.pop();
+            lines.push(String::from_utf8_lossy(&buf).into_owned());
+        }
+
+        Ok((lines, next))
+    }
+
+    /// Blocks until at least one complete line exists past `offset`, then
+    /// returns all of them together with the new offset.
+    /// Fails with `Error::Timeout` once `deadline` passes without new lines.
+    pub fn watch_from(&self, offset: u64, deadline: Option<Instant>) -> Result<(Vec<String>, u64)> {
+        let (_, lines, next) = Self::watch_many(&[(self, offset)], deadline)?;
+        Ok((lines, next))
+    }
+
+    /// `watch_from` over several Chatfiles with a single watcher. Returns the
+    /// index of the first room with new lines, its lines and its new offset.
+    pub fn watch_many(
+        rooms: &[(&Chatfile, u64)],
+        deadline: Option<Instant>,
+    ) -> Result<(usize, Vec<String>, u64)> {
+        let mode = WatchMode::for_paths(rooms.iter().map(|(room, _)| room.path.as_path()));
+        let identities = rooms
+            .iter()
+            .map(|(room, _)| room.identity())
+            .collect::<Result<Vec<_>>>()?;
 
-        watcher
-            .watch(&self.path, RecursiveMode::NonRecursive)
-            .map_err(|e| Error::Io(std::io::Error::other(format!("watch error: {e}"))))?;
+        // Watch before the first read so appends in between are not missed
+        let notifications = match mode {
+            WatchMode::Notify => Some(Self::notify_watcher(rooms)?),
+            WatchMode::Poll(_) => None,
+        };
 
-        log::debug("Chatfile", "Waiting for new message (inotify)...");
+        log::debug(
+            "Chatfile",
+            &format!(
+                "Waiting for new lines in {} room(s) ({mode:?})...",
+                rooms.len()
+            ),
+        );
 
-        // Wait for file modification events
         loop {
-            // Block until we get a notification (with timeout to prevent infinite hang)
-            match rx.recv_timeout(Duration::from_secs(60)) {
-                Ok(()) => {
-                    // File was modified, check for new lines
-                    let current_lines = self.count_lines()?;
-                    if current_lines > initial_lines {
-                        // Read the last line
-                        if let Some(line) = self.last_line()? {
-                            return Ok(line);
-                        }
-                    }
+            for (i, (room, offset)) in rooms.iter().enumerate() {
+                room.check_unchanged(identities[i], *offset)?;
+                let (lines, next) = room.read_from(*offset)?;
+                if !lines.is_empty() {
+                    return Ok((i, lines, next));
                 }
-                Err(mpsc::RecvTimeoutError::Timeout) => {
-                    // Timeout - just continue waiting
-                    continue;
+            }
+
+            let mut wait = match mode {
+                WatchMode::Notify => Duration::from_secs(60),
+                WatchMode::Poll(interval) => interval,
+            };
+            if let Some(deadline) = deadline {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                if remaining.is_zero() {
+                    return Err(Error::Timeout);
                 }
+                wait = wait.min(remaining);
+            }
+
+            let Some((_watcher, rx)) = &notifications else {
+                std::thread::sleep(wait);
+                continue;
+            };
+            match rx.recv_timeout(wait) {
+                Ok(()) | Err(mpsc::RecvTimeoutError::Timeout) => continue,
                 Err(mpsc::RecvTimeoutError::Disconnected) => {
                     return Err(Error::Io(std::io::Error::other("watcher disconnected")));
                 }
@@ -193,9 +596,89 @@ impl Chatfile {
         }
     }
 
-    fn count_lines(&self) -> Result<usize> {
-        let file = File::open(&self.path)?;
-        let reader = BufReader::new(file);
-        Ok(reader.lines().count())
+    /// Identifies the file currently at `path` (device and inode on unix),
+    /// to tell an append from a replacement.
+    #[cfg(unix)]
+    fn identity(&self) -> Result<Option<(u64, u64)>> {
+        use std::os::unix::fs::MetadataExt;
+
+        match std::fs::metadata(&self.path) {
+            Ok(meta) => Ok(Some((meta.dev(), meta.ino()))),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                Err(Error::RoomClosed(self.path.clone()))
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn identity(&self) -> Result<Option<(u64, u64)>> {
+        Ok(None)
+    }
+
+    /// Returns the inode of the file currently at `path`, `None` where
+    /// there are no inodes.
+    pub fn inode(&self) -> Result<Option<u64>> {
+        Ok(self.identity()?.map(|(_, inode)| inode))
     }
+
+    /// Fails if the Chatfile was deleted, replaced by another file, or
+    /// truncated below `offset` since `identity` was taken.
+    fn check_unchanged(&self, identity: Option<(u64, u64)>, offset: u64) -> Result<()> {
+        let size = match std::fs::metadata(&self.path) {
+            Ok(meta) => meta.len(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                log::warn("Chatfile", &format!("Deleted: {}", self.path.display()));
+                return Err(Error::RoomClosed(self.path.clone()));
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        if self.identity()? != identity || size < offset {
+            log::warn("Chatfile", &format!("Rotated: {}", self.path.display()));
+            return Err(Error::RoomRotated(self.path.clone()));
+        }
+        Ok(())
+    }
+
+    /// Starts a notification watcher on every room; the receiver gets a
+    /// message for each change, including deletion and replacement.
+    fn notify_watcher(
+        rooms: &[(&Chatfile, u64)],
+    ) -> Result<(notify::RecommendedWatcher, mpsc::Receiver<()>)> {
+        let (tx, rx) = mpsc::channel();
+        let mut watcher = notify::recommended_watcher(move |res: std::result::Result<Event, _>| {
+            if let Ok(event) = res {
+                if !event.kind.is_access() {
+                    let _ = tx.send(());
+                }
+            }
+        })
+        .map_err(|e| Error::Io(std::io::Error::other(format!("watcher error: {e}"))))?;
+
+        for (room, _) in rooms {
+            watcher
+                .watch(&room.path, RecursiveMode::NonRecursive)
+                .map_err(|e| Error::Io(std::io::Error::other(format!("watch error: {e}"))))?;
+        }
+
+        Ok((watcher, rx))
+    }
+
+    /// Blocks until new lines are appended and returns the last of them.
+    pub fn watch(&self) -> Result<String> {
+        let offset = self.size()?;
+        let (mut lines, _) = self.watch_from(offset, None)?;
+        lines
+            .pop()
+            .ok_or_else(|| Error::Io(std::io::Error::other("watch returned no lines")))
+    }
+}
+
+/// Local time truncated to whole seconds, as written to the Chatfile.
+pub(crate) fn now() -> NaiveDateTime {
+    Local::now()
+        .naive_local()
+        .with_nanosecond(0)
+        .unwrap_or_default()
 }
@@ -1,13 +1,19 @@
-use std::path::PathBuf;
-use std::process::Command;
-
-use directories::ProjectDirs;
+use std::path::{Path, PathBuf};
 
+use super::acl;
+use super::dirs;
 use super::error::Result;
+use super::lines;
+use super::moderation;
+use super::presence;
+use super::protect;
+use super::rate;
 
 #[derive(Debug, Default)]
 pub struct ClearableFiles {
     pub chatfiles: Vec<PathBuf>,
+    /// Files and directories kept next to `chatfiles`, which go with them.
+    pub sidecars: Vec<PathBuf>,
     pub sessions: Vec<PathBuf>,
     pub admin: Option<PathBuf>,
 }
@@ -18,7 +24,10 @@ impl ClearableFiles {
     }
 
     pub fn total_count(&self) -> usize {
-        self.chatfiles.len() + self.sessions.len() + if self.admin.is_some() { 1 } else { 0 }
+        self.chatfiles.len()
+            + self.sidecars.len()
+            + self.sessions.len()
+            + if self.admin.is_some() { 1 } else { 0 }
     }
 }
 
@@ -49,13 +58,13 @@ pub fn list_clearable_files(sessions_only: bool) -> Result<ClearableFiles> {
     }
 
     // Check XDG sessions directory
-    if let Some(proj_dirs) = ProjectDirs::from("", "", "chatfiles") {
-        let sessions_dir = proj_dirs.data_dir().join("sessions");
+    if let Ok(sessions_dir) = dirs::sessions_dir() {
         if sessions_dir.exists() {
             if let Ok(entries) = std::fs::read_dir(&sessions_dir) {
                 for entry in entries.flatten() {
                     let path = entry.path();
-                    if path.extension().is_some_and(|ext| ext == "session") {
+                    let is_pins = path.file_name().is_some_and(|n| n == "pins");
+                    if is_pins || path.extension().is_some_and(|ext| ext == "session") {
                         files.sessions.push(path);
                     }
                 }
@@ -73,6 +82,7 @@ pub fn list_clearable_files(sessions_only: bool) -> Result<ClearableFiles> {
 
     files.chatfiles.sort();
     files.sessions.sort();
+    files.sidecars = files.chatfiles.iter().flat_map(|p| sidecars(p)).collect();
 
     Ok(files)
 }
@@ -81,10 +91,12 @@ pub fn clear_files(files: &ClearableFiles) -> ClearResult {
     let mut result = ClearResult::default();
 
     for path in &files.chatfiles {
-        if let Err(e) = remove_file_with_chattr(path) {
-            result.failed.push((path.clone(), e));
+        let sidecars = sidecars(path);
+        if let Err(e) = remove_room(path) {
+            result.failed.push((path.clone(), e.to_string()));
         } else {
             result.removed.push(path.clone());
+            result.removed.extend(sidecars);
         }
     }
 
@@ -107,16 +119,42 @@ pub fn clear_files(files: &ClearableFiles) -> ClearResult {
     result
 }
 
-fn remove_file_with_chattr(path: &PathBuf) -> std::result::Result<(), String> {
-    #[cfg(target_os = "linux")]
-    {
-        let _ = Command::new("sudo")
-            .args(["chattr", "-a"])
-            .arg(path)
-            .status();
+/// Deletes the room at `path`, and with it the files kept next to it:
+/// heartbeats, send logs, roles, cached standings, line counts and the
+/// snapshot mean nothing without the room.
+pub fn remove_room(path: &Path) -> Result<()> {
+    protect::unprotect(path)?;
+    std::fs::remove_file(path)?;
+
+    for sidecar in sidecar_paths(path) {
+        let _ = if sidecar.is_dir() {
+            std::fs::remove_dir_all(&sidecar)
+        } else {
+            std::fs::remove_file(&sidecar)
+        };
     }
+    Ok(())
+}
 
-    std::fs::remove_file(path).map_err(|e| e.to_string())
+/// Returns the sidecars of the room at `path` that exist.
+fn sidecars(path: &Path) -> Vec<PathBuf> {
+    sidecar_paths(path)
+        .into_iter()
+        .filter(|p| p.symlink_metadata().is_ok())
+        .collect()
+}
+
+/// Returns every file and directory that may be kept next to the room at
+/// `path`.
+fn sidecar_paths(path: &Path) -> [PathBuf; 6] {
+    [
+        presence::dir(path),
+        rate::dir(path),
+        acl::path(path),
+        moderation::path(path),
+        lines::path(path),
+        protect::snapshot_path(path),
+    ]
 }
 
 pub fn format_file_list(files: &ClearableFiles) -> String {
@@ -129,6 +167,13 @@ pub fn format_file_list(files: &ClearableFiles) -> String {
         }
     }
 
+    if !files.sidecars.is_empty() {
+        lines.push("Room data:".to_string());
+        for f in &files.sidecars {
+            lines.push(format!("  {}", f.display()));
+        }
+    }
+
     if !files.sessions.is_empty() {
         lines.push("Session files:".to_string());
         for f in &files.sessions {
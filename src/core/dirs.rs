@@ -1,20 +1,99 @@
+//! Where chatfiles keeps its own files. Every lookup of the data and
+//! config directories goes through here.
+//!
+//! `CF_HOME` replaces both directories, e.g. for tests and sandboxes.
+
 use directories::ProjectDirs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::Once;
 
 use super::error::{Error, Result};
+use crate::log;
+
+static MIGRATE: Once = Once::new();
 
 pub fn project_dirs() -> Result<ProjectDirs> {
     ProjectDirs::from("io", "ryugen", "chatfiles").ok_or(Error::XdgError)
 }
 
+/// Returns `CF_HOME` if set.
+fn home_override() -> Option<PathBuf> {
+    std::env::var_os("CF_HOME")
+        .filter(|v| !v.is_empty())
+        .map(PathBuf::from)
+}
+
 pub fn data_dir() -> Result<PathBuf> {
+    if let Some(home) = home_override() {
+        return Ok(home);
+    }
+    MIGRATE.call_once(migrate_legacy);
     Ok(project_dirs()?.data_dir().to_path_buf())
 }
 
 pub fn config_dir() -> Result<PathBuf> {
+    if let Some(home) = home_override() {
+        return Ok(home);
+    }
+    MIGRATE.call_once(migrate_legacy);
     Ok(project_dirs()?.config_dir().to_path_buf())
 }
 
 pub fn sessions_dir() -> Result<PathBuf> {
     Ok(data_dir()?.join("sessions"))
 }
+
+/// Moves sessions and the admin file out of the directories older versions
+/// used (`ProjectDirs::from("", "", "chatfiles")`). These only differ from
+/// the current ones on macOS and Windows. Failures are logged, not fatal.
+fn migrate_legacy() {
+    let (Some(legacy), Ok(current)) = (ProjectDirs::from("", "", "chatfiles"), project_dirs())
+    else {
+        return;
+    };
+
+    if legacy.data_dir() != current.data_dir() {
+        let from = legacy.data_dir().join("sessions");
+        let to = current.data_dir().join("sessions");
+        if let Ok(entries) = std::fs::read_dir(&from) {
+            for entry in entries.flatten() {
+                move_file(&entry.path(), &to.join(entry.file_name()));
+            }
+            let _ = std::fs::remove_dir(&from);
+        }
+    }
+
+    if legacy.config_dir() != current.config_dir() {
+        let name = ".cf_admin";
+        let from = legacy.config_dir().join(name);
+        if from.exists() {
+            move_file(&from, &current.config_dir().join(name));
+        }
+    }
+}
+
+/// Moves `from` to `to` unless `to` already exists.
+fn move_file(from: &Path, to: &Path) {
+    if to.exists() {
+        return;
+    }
+
+    let moved = to
+        .parent()
+        .map_or(Ok(()), std::fs::create_dir_all)
+        .and_then(|()| {
+            // rename fails across filesystems; fall back to copying
+            std::fs::rename(from, to).or_else(|_| {
+                std::fs::copy(from, to)?;
+                std::fs::remove_file(from)
+            })
+        });
+
+    match moved {
+        Ok(()) => log::info(
+            "dirs",
+            &format!("Moved {} to {}", from.display(), to.display()),
+        ),
+        Err(e) => log::warn("dirs", &format!("Could not move {}: {e}", from.display())),
+    }
+}
@@ -0,0 +1,47 @@
+use std::time::Duration;
+
+/// Parses durations like `500ms`, `30s`, `5m`, `1h` or `7d`. A bare number is
+/// seconds. Durations must fit in `u64` milliseconds, so callers can do
+/// millisecond arithmetic on them.
+pub fn parse(s: &str) -> Result<Duration, String> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (value, unit) = s.split_at(split);
+
+    let value: u64 = value
+        .parse()
+        .map_err(|_| format!("invalid duration: {s}"))?;
+
+    let ms_per_unit: u64 = match unit {
+        "ms" => 1,
+        "" | "s" => 1000,
+        "m" => 60 * 1000,
+        "h" => 3600 * 1000,
+        "d" => 86400 * 1000,
+        _ => {
+            return Err(format!(
+                "invalid duration unit: {unit} (use ms, s, m, h or d)"
+            ));
+        }
+    };
+    value
+        .checked_mul(ms_per_unit)
+        .map(Duration::from_millis)
+        .ok_or_else(|| format!("duration too large: {s}"))
+}
+
+/// Formats `d` in the largest unit that `parse` reads back exactly, e.g.
+/// `90s` or `2h`.
+pub fn format(d: Duration) -> String {
+    let ms = d.as_millis();
+    if ms % 1000 != 0 {
+        return format!("{ms}ms");
+    }
+    let secs = ms / 1000;
+    for (unit, size) in [("d", 86400), ("h", 3600), ("m", 60)] {
+        if secs != 0 && secs % size == 0 {
+            return format!("{}{unit}", secs / size);
+        }
+    }
+    format!("{secs}s")
+}
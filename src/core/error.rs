@@ -30,17 +30,128 @@ pub enum Error {
     #[error("Invalid session file format")]
     InvalidSession,
 
+    #[error("No session matches {0}. Run: cf sessions")]
+    UnknownSession(String),
+
+    #[error("Several sessions match {0}. Pick one with --room or its id (cf sessions)")]
+    AmbiguousSession(String),
+
     #[error("Permission denied: {0}")]
     PermissionDenied(String),
 
     #[error("Admin privileges required. Create .cf_admin file to enable.")]
     NotAdmin,
 
+    #[error("You were removed from this room by {0}. Run: cf join")]
+    Kicked(String),
+
+    #[error("Muted by {0}")]
+    Muted(String),
+
+    #[error("Banned from this room by {0}")]
+    Banned(String),
+
+    #[error("Message is too long ({0} characters, the room allows {1})")]
+    MessageTooLong(usize, usize),
+
+    #[error("Rate limit of {0} reached. Retry in {1}s")]
+    RateLimited(String, u64),
+
     #[error("Invalid name: {0}")]
     InvalidName(String),
 
+    #[error("Name already taken: {0}")]
+    NameTaken(String),
+
     #[error("Failed to determine XDG base directories")]
     XdgError,
+
+    #[error("Timed out waiting for message")]
+    Timeout,
+
+    #[error("No message with id {0}")]
+    UnknownMessage(u64),
+
+    #[error("Room closed: {0} was deleted")]
+    RoomClosed(PathBuf),
+
+    #[error("Room rotated: {0} was truncated or replaced")]
+    RoomRotated(PathBuf),
+
+    #[error("Room tampered with: {0}")]
+    Tampered(String),
+}
+
+impl Error {
+    /// Returns the process exit code `cf` reports for this error.
+    ///
+    /// Codes are stable so scripts can branch on them; they are grouped by
+    /// area (1x session, 2x room, 3x message, 4x permissions, 5x
+    /// environment). `Timeout` uses 124 like coreutils `timeout`, and 2 is
+    /// left to clap for usage errors.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Self::Io(_) => 1,
+            Self::NoSession => 10,
+            Self::InvalidSession => 11,
+            Self::NotJoined => 12,
+            Self::AlreadyJoined(_) => 13,
+            Self::UnknownSession(_) => 14,
+            Self::AmbiguousSession(_) => 15,
+            Self::ChatfileNotFound(_) => 20,
+            Self::RoomExists(_) => 21,
+            Self::RoomClosed(_) => 22,
+            Self::RoomRotated(_) => 23,
+            Self::Tampered(_) => 25,
+            Self::EmptyMessage => 30,
+            Self::InvalidName(_) => 31,
+            Self::NameGenerationFailed(_) => 32,
+            Self::UnknownMessage(_) => 33,
+            Self::NameTaken(_) => 34,
+            Self::MessageTooLong(..) => 35,
+            Self::RateLimited(..) => 36,
+            Self::PermissionDenied(_) => 40,
+            Self::NotAdmin => 41,
+            Self::Kicked(_) => 42,
+            Self::Muted(_) => 43,
+            Self::Banned(_) => 44,
+            Self::XdgError => 50,
+            Self::Timeout => 124,
+        }
+    }
+
+    /// Returns a stable snake_case identifier for this error, for
+    /// machine-readable output.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Io(_) => "io",
+            Self::NoSession => "no_session",
+            Self::InvalidSession => "invalid_session",
+            Self::NotJoined => "not_joined",
+            Self::AlreadyJoined(_) => "already_joined",
+            Self::UnknownSession(_) => "unknown_session",
+            Self::AmbiguousSession(_) => "ambiguous_session",
+            Self::ChatfileNotFound(_) => "chatfile_not_found",
+            Self::RoomExists(_) => "room_exists",
+            Self::RoomClosed(_) => "room_closed",
+            Self::RoomRotated(_) => "room_rotated",
+            Self::Tampered(_) => "tampered",
+            Self::EmptyMessage => "empty_message",
+            Self::InvalidName(_) => "invalid_name",
+            Self::NameGenerationFailed(_) => "name_generation_failed",
+            Self::UnknownMessage(_) => "unknown_message",
+            Self::NameTaken(_) => "name_taken",
+            Self::MessageTooLong(..) => "message_too_long",
+            Self::RateLimited(..) => "rate_limited",
+            Self::PermissionDenied(_) => "permission_denied",
+            Self::NotAdmin => "not_admin",
+            Self::Kicked(_) => "kicked",
+            Self::Muted(_) => "muted",
+            Self::Banned(_) => "banned",
+            Self::XdgError => "xdg_error",
+            Self::Timeout => "timeout",
+        }
+    }
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
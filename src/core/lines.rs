@@ -0,0 +1,56 @@
+//! How many lines a room has up to some byte offset, cached next to it.
+//!
+//! Message ids are line numbers, so replying and numbering a tail of the
+//! room need the count of every line before it. The count is cached in
+//! `.<chatfile name>.lines` together with the offset it covers, and each
+//! call only counts the bytes between that offset and the one it needs,
+//! in either direction. A replaced or truncated room, or a missing or
+//! unreadable cache, is counted from the start.
+
+use std::path::{Path, PathBuf};
+
+use super::error::Result;
+use super::sidecar;
+
+/// Returns the line count cache of `chatfile`.
+pub fn path(chatfile: &Path) -> PathBuf {
+    sidecar::path(chatfile, "lines")
+}
+
+/// `lines` newlines in the first `offset` bytes of the room with inode
+/// `inode`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct LineCount {
+    pub offset: u64,
+    pub inode: u64,
+    pub lines: u64,
+}
+
+impl LineCount {
+    /// Loads the cache of `chatfile`, or `None` if it is missing or
+    /// unreadable.
+    pub fn load(chatfile: &Path) -> Option<Self> {
+        let content = std::fs::read_to_string(path(chatfile)).ok()?;
+        let field = |key: &str| {
+            content
+                .lines()
+                .find_map(|l| l.strip_prefix(key)?.strip_prefix('='))?
+                .parse()
+                .ok()
+        };
+        Some(Self {
+            offset: field("offset")?,
+            inode: field("inode")?,
+            lines: field("lines")?,
+        })
+    }
+
+    /// Writes the cache of `chatfile`.
+    pub fn save(&self, chatfile: &Path) -> Result<()> {
+        let content = format!(
+            "offset={}\ninode={}\nlines={}\n",
+            self.offset, self.inode, self.lines
+        );
+        sidecar::write_atomic(&path(chatfile), &content)
+    }
+}
@@ -0,0 +1,42 @@
+use std::fs::File;
+
+use super::error::Result;
+
+/// Exclusive advisory `flock` on a file, released when dropped.
+#[derive(Debug)]
+pub struct FileLock {
+    file: File,
+}
+
+impl FileLock {
+    /// Blocks until an exclusive lock on `file` is acquired.
+    #[cfg(unix)]
+    pub fn exclusive(file: File) -> Result<Self> {
+        use std::os::fd::AsRawFd;
+
+        // SAFETY: flock only operates on the descriptor, which `file` keeps open
+        while unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+            let err = std::io::Error::last_os_error();
+            if err.kind() != std::io::ErrorKind::Interrupted {
+                return Err(err.into());
+            }
+        }
+        Ok(Self { file })
+    }
+
+    #[cfg(not(unix))]
+    pub fn exclusive(file: File) -> Result<Self> {
+        Ok(Self { file })
+    }
+}
+
+impl Drop for FileLock {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        {
+            use std::os::fd::AsRawFd;
+            // SAFETY: the descriptor is still owned by `self.file`
+            unsafe { libc::flock(self.file.as_raw_fd(), libc::LOCK_UN) };
+        }
+    }
+}
@@ -0,0 +1,415 @@
+use std::borrow::Cow;
+use std::fmt;
+
+use chrono::NaiveDateTime;
+
+use super::chain;
+
+const TIMESTAMP_FORMAT: &str = "%F %T";
+
+/// Mention that addresses everyone in the room.
+pub const MENTION_ALL: &str = "all";
+
+/// What a moderation record does to its target.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Moderation {
+    /// Removes the target from the room; it may join again
+    Kick,
+    /// Stops the target from sending, until the given time or until unmuted
+    Mute {
+        until: Option<NaiveDateTime>,
+    },
+    Unmute,
+    /// Removes the target and stops it from joining again
+    Ban,
+    Unban,
+}
+
+impl Moderation {
+    const VERBS: [(&str, Self); 5] = [
+        ("kicked", Self::Kick),
+        ("muted", Self::Mute { until: None }),
+        ("unmuted", Self::Unmute),
+        ("banned", Self::Ban),
+        ("unbanned", Self::Unban),
+    ];
+
+    /// The past tense used in the record, e.g. `kicked`.
+    pub fn verb(&self) -> &'static str {
+        match self {
+            Self::Kick => "kicked",
+            Self::Mute { .. } => "muted",
+            Self::Unmute => "unmuted",
+            Self::Ban => "banned",
+            Self::Unban => "unbanned",
+        }
+    }
+}
+
+/// A single Chatfile line, parsed into the record that wrote it.
+///
+/// Chat, join, leave, rename and moderation lines in rooms created with timestamps
+/// carry a `[YYYY-MM-DD HH:MM:SS] ` prefix; lines without one parse with
+/// `None`.
+///
+/// `Message::parse` and the `Display` impl round-trip every line the
+/// protocol writes, except that hash chain tags are dropped; anything else
+/// is preserved verbatim as `Other`.
+/// Message text is held unescaped and may span several lines; `Display`
+/// escapes it back into a single Chatfile line (see `escape`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    /// `name: text`, written by `Chatfile::send`
+    Chat {
+        name: String,
+        text: String,
+        timestamp: Option<NaiveDateTime>,
+    },
+    /// `[name joined]`, written by `Chatfile::announce_join`
+    Join {
+        name: String,
+        timestamp: Option<NaiveDateTime>,
+    },
+    /// `[name left]`, written by `Chatfile::announce_leave`
+    Leave {
+        name: String,
+        timestamp: Option<NaiveDateTime>,
+    },
+    /// `[from renamed to to]`, written by `Chatfile::announce_rename`
+    Rename {
+        from: String,
+        to: String,
+        timestamp: Option<NaiveDateTime>,
+    },
+    /// `[target was kicked by by]`, `[target was muted by by until <timestamp>]`
+    /// and so on, written by `Chatfile::announce_moderation`
+    Moderation {
+        target: String,
+        action: Moderation,
+        by: String,
+        timestamp: Option<NaiveDateTime>,
+    },
+    /// `[system <timestamp>]: text`, the room header written by `Chatfile::create`
+    System {
+        timestamp: NaiveDateTime,
+        text: String,
+    },
+    /// `[prefix]: text`, written by `admin::admin_send`
+    Admin { prefix: String, text: String },
+    /// Any line that does not match a known record
+    Other(String),
+}
+
+impl Message {
+    pub fn parse(line: &str) -> Self {
+        let (stamp, rest) = split_timestamp(line);
+
+        let mut msg = Self::parse_record(rest).unwrap_or_else(|| Self::Other(line.to_string()));
+        if stamp.is_some() {
+            match &mut msg {
+                Self::Chat { timestamp, .. }
+                | Self::Join { timestamp, .. }
+                | Self::Leave { timestamp, .. }
+                | Self::Rename { timestamp, .. }
+                | Self::Moderation { timestamp, .. } => *timestamp = stamp,
+                // Only chat, join, leave, rename and moderation lines are
+                // ever stamped
+                _ => return Self::Other(line.to_string()),
+            }
+        }
+        msg
+    }
+
+    fn parse_record(line: &str) -> Option<Self> {
+        if let Some(msg) = Self::parse_bracketed(line) {
+            return Some(msg);
+        }
+
+        let (name, rest) = split_chat(line)?;
+        let text = rest.strip_prefix(' ')?;
+        Some(Self::Chat {
+            name: name.to_string(),
+            text: unescape(text).into_owned(),
+            timestamp: None,
+        })
+    }
+
+    fn parse_bracketed(line: &str) -> Option<Self> {
+        let inner = line.strip_prefix('[')?;
+
+        // [name joined] / [name left] / [from renamed to to]
+        if let Some(body) = inner.strip_suffix(']') {
+            if !body.contains(['[', ']']) {
+                if let Some(name) = body.strip_suffix(" joined") {
+                    return Some(Self::Join {
+                        name: name.to_string(),
+                        timestamp: None,
+                    });
+                }
+                if let Some(name) = body.strip_suffix(" left") {
+                    return Some(Self::Leave {
+                        name: name.to_string(),
+                        timestamp: None,
+                    });
+                }
+                if let Some((from, to)) = body.split_once(" renamed to ") {
+                    return Some(Self::Rename {
+                        from: from.to_string(),
+                        to: to.to_string(),
+                        timestamp: None,
+                    });
+                }
+                if let Some(msg) = Self::parse_moderation(body) {
+                    return Some(msg);
+                }
+            }
+        }
+
+        // [system <timestamp>]: text / [prefix]: text
+        let (tag, text) = inner.split_once("]: ")?;
+        if tag.contains(['[', ']']) {
+            return None;
+        }
+
+        if let Some(ts) = tag.strip_prefix("system ") {
+            if let Ok(timestamp) = NaiveDateTime::parse_from_str(ts, TIMESTAMP_FORMAT) {
+                return Some(Self::System {
+                    timestamp,
+                    text: unescape(text).into_owned(),
+                });
+            }
+        }
+
+        Some(Self::Admin {
+            prefix: tag.to_string(),
+            text: unescape(text).into_owned(),
+        })
+    }
+
+    /// Parses the body of `[target was <verb> by by]`, where a mute may end
+    /// in ` until <timestamp>`.
+    fn parse_moderation(body: &str) -> Option<Self> {
+        Moderation::VERBS.iter().find_map(|(verb, action)| {
+            let (target, by) = body.split_once(&format!(" was {verb} by "))?;
+            let (by, action) = match action {
+                Moderation::Mute { .. } => match by.rsplit_once(" until ") {
+                    Some((by, until)) => {
+                        let until = NaiveDateTime::parse_from_str(until, TIMESTAMP_FORMAT).ok()?;
+                        (by, Moderation::Mute { until: Some(until) })
+                    }
+                    None => (by, *action),
+                },
+                _ => (by, *action),
+            };
+            Some(Self::Moderation {
+                target: target.to_string(),
+                action,
+                by: by.to_string(),
+                timestamp: None,
+            })
+        })
+    }
+
+    /// Returns the sender of a chat message, `None` for every other record.
+    pub fn sender(&self) -> Option<&str> {
+        match self {
+            Self::Chat { name, .. } => Some(name),
+            _ => None,
+        }
+    }
+
+    /// Returns the time a chat, join, leave, rename or moderation line was
+    /// written, if stamped.
+    pub fn timestamp(&self) -> Option<NaiveDateTime> {
+        match self {
+            Self::Chat { timestamp, .. }
+            | Self::Join { timestamp, .. }
+            | Self::Leave { timestamp, .. }
+            | Self::Rename { timestamp, .. }
+            | Self::Moderation { timestamp, .. } => *timestamp,
+            _ => None,
+        }
+    }
+
+    /// Returns the message body for records that carry one.
+    pub fn text(&self) -> Option<&str> {
+        match self {
+            Self::Chat { text, .. } | Self::System { text, .. } | Self::Admin { text, .. } => {
+                Some(text)
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns the names @mentioned in a chat message.
+    pub fn mentions(&self) -> Vec<&str> {
+        match self {
+            Self::Chat { text, .. } => mentions(text),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Checks whether a chat message addresses `name` directly or via `@all`.
+    pub fn mentions_name(&self, name: &str) -> bool {
+        self.mentions()
+            .iter()
+            .any(|&m| m == name || m == MENTION_ALL)
+    }
+
+    /// Formats the message for reading, with line breaks in the text kept
+    /// as real line breaks instead of escapes.
+    pub fn to_plain(&self) -> String {
+        let mut out = String::new();
+        // Writing into a String cannot fail
+        let _ = self.write(&mut out, |t| Cow::Borrowed(t));
+        out
+    }
+
+    fn write(&self, f: &mut impl fmt::Write, text: fn(&str) -> Cow<'_, str>) -> fmt::Result {
+        if let Some(ts) = self.timestamp() {
+            write!(f, "[{}] ", ts.format(TIMESTAMP_FORMAT))?;
+        }
+
+        match self {
+            Self::Chat { name, text: t, .. } => write!(f, "{name}: {}", text(t)),
+            Self::Join { name, .. } => write!(f, "[{name} joined]"),
+            Self::Leave { name, .. } => write!(f, "[{name} left]"),
+            Self::Rename { from, to, .. } => write!(f, "[{from} renamed to {to}]"),
+            Self::Moderation {
+                target, action, by, ..
+            } => {
+                write!(f, "[{target} was {} by {by}", action.verb())?;
+                if let Moderation::Mute { until: Some(until) } = action {
+                    write!(f, " until {}", until.format(TIMESTAMP_FORMAT))?;
+                }
+                f.write_str("]")
+            }
+            Self::System { timestamp, text: t } => write!(
+                f,
+                "[system {}]: {}",
+                timestamp.format(TIMESTAMP_FORMAT),
+                text(t)
+            ),
+            Self::Admin { prefix, text: t } => write!(f, "[{prefix}]: {}", text(t)),
+            Self::Other(line) => f.write_str(line),
+        }
+    }
+}
+
+impl fmt::Display for Message {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.write(f, escape)
+    }
+}
+
+/// Escapes message text so it fits on one Chatfile line.
+///
+/// Line feeds become `\n` and carriage returns `\r`. A backslash is only
+/// doubled where it would otherwise be read as part of an escape (before
+/// `\`, `n`, `r` or a line break), so ordinary text like `C:\dir` or
+/// `\d+` is written unchanged. Lines written before escaping existed are
+/// unescaped too, so a literal `\n` in them now reads as a line break.
+pub fn escape(text: &str) -> Cow<'_, str> {
+    if !text.contains(['\\', '\n', '\r']) {
+        return Cow::Borrowed(text);
+    }
+
+    let mut out = String::with_capacity(text.len() + 8);
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\\' => {
+                out.push('\\');
+                if matches!(chars.peek(), Some('\\' | 'n' | 'r' | '\n' | '\r')) {
+                    out.push('\\');
+                }
+            }
+            c => out.push(c),
+        }
+    }
+    Cow::Owned(out)
+}
+
+/// Reverses `escape`. Backslashes that don't start a known escape are
+/// kept as they are.
+pub fn unescape(text: &str) -> Cow<'_, str> {
+    if !text.contains('\\') {
+        return Cow::Borrowed(text);
+    }
+
+    let mut out = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.peek() {
+            Some('\\') => out.push('\\'),
+            Some('n') => out.push('\n'),
+            Some('r') => out.push('\r'),
+            _ => {
+                out.push('\\');
+                continue;
+            }
+        }
+        chars.next();
+    }
+    Cow::Owned(out)
+}
+
+/// Returns the names @mentioned in `text`, e.g. `@swift-fox-1234`.
+/// An `@` preceded by a letter or digit (as in an email address) is not a
+/// mention, and a trailing `.` is taken as punctuation.
+pub fn mentions(text: &str) -> Vec<&str> {
+    let is_name_char = |c: char| c.is_alphanumeric() || matches!(c, '-' | '_' | '.');
+
+    let mut found = Vec::new();
+    let mut prev = None;
+    for (i, c) in text.char_indices() {
+        if c == '@' && !prev.is_some_and(char::is_alphanumeric) {
+            let rest = &text[i + 1..];
+            let end = rest.find(|c| !is_name_char(c)).unwrap_or(rest.len());
+            let name = rest[..end].trim_end_matches('.');
+            if !name.is_empty() {
+                found.push(name);
+            }
+        }
+        prev = Some(c);
+    }
+    found
+}
+
+/// Splits off a leading `[YYYY-MM-DD HH:MM:SS] ` stamp, if present.
+/// A hash chain tag before it is dropped (see `chain`).
+pub(crate) fn split_timestamp(line: &str) -> (Option<NaiveDateTime>, &str) {
+    let (_, line) = chain::split(line);
+    let stamp = line.strip_prefix('[').and_then(|rest| {
+        // 19 chars of "YYYY-MM-DD HH:MM:SS", then "] "
+        let (ts, rest) = rest.split_at_checked(19)?;
+        let rest = rest.strip_prefix("] ")?;
+        let ts = NaiveDateTime::parse_from_str(ts, TIMESTAMP_FORMAT).ok()?;
+        Some((ts, rest))
+    });
+
+    match stamp {
+        Some((ts, rest)) => (Some(ts), rest),
+        None => (None, line),
+    }
+}
+
+/// Splits a `name: message` line at the first colon.
+/// Returns None for system lines (starting with `[`) or lines without sender.
+/// Expects any timestamp prefix to be stripped already.
+pub(crate) fn split_chat(line: &str) -> Option<(&str, &str)> {
+    if line.starts_with('[') {
+        return None;
+    }
+    let colon_pos = line.find(':')?;
+    if colon_pos == 0 {
+        return None;
+    }
+    Some((&line[..colon_pos], &line[colon_pos + 1..]))
+}
@@ -1,12 +1,29 @@
+pub mod acl;
 pub mod admin;
+pub mod chain;
 pub mod chatfile;
 pub mod clear;
 pub mod dirs;
+pub mod duration;
 pub mod error;
+pub mod lines;
+pub mod lock;
+pub mod message;
+pub mod moderation;
 pub mod names;
 pub mod ops;
+pub mod pins;
+pub mod presence;
+pub mod protect;
+pub mod rate;
+pub mod room;
 pub mod session;
+pub mod sidecar;
+pub mod thread;
+pub mod watch;
 
 pub use chatfile::Chatfile;
 pub use error::{Error, Result};
-pub use session::Session;
+pub use message::Message;
+pub use room::{RoomOptions, RosterEntry};
+pub use session::{Session, SessionEntry, SessionSelector};
@@ -0,0 +1,144 @@
+//! Who is kicked, muted or banned, replayed from a room's moderation
+//! records.
+//!
+//! Every send and join needs the sender's standing, and replaying the
+//! whole room for it would make them as slow as the room is large. The
+//! replayed standings are therefore cached next to the room, in
+//! `.<chatfile name>.moderation`, together with the byte offset they cover,
+//! so each call only replays the lines appended since. The Chatfile stays
+//! the record: a replaced or truncated room, or a missing or unreadable
+//! cache, is replayed from the start.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use chrono::NaiveDateTime;
+
+use super::error::Result;
+use super::message::{Message, Moderation};
+use super::room::Standing;
+use super::sidecar;
+
+const TIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+
+/// Returns the standings cache of `chatfile`.
+pub fn path(chatfile: &Path) -> PathBuf {
+    sidecar::path(chatfile, "moderation")
+}
+
+/// Standings replayed up to `offset` of the room with inode `inode`. Names
+/// with a default standing are left out.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Standings {
+    pub offset: u64,
+    pub inode: u64,
+    standings: HashMap<String, Standing>,
+}
+
+impl Standings {
+    /// Loads the cache of `chatfile`, or `None` if it is missing or
+    /// unreadable.
+    pub fn load(chatfile: &Path) -> Option<Self> {
+        let content = std::fs::read_to_string(path(chatfile)).ok()?;
+
+        let mut cache = Self::default();
+        let mut current: Option<(String, Standing)> = None;
+        for line in content.lines() {
+            let (key, value) = line.split_once('=')?;
+            match key {
+                "offset" => cache.offset = value.parse().ok()?,
+                "inode" => cache.inode = value.parse().ok()?,
+                "name" => {
+                    if let Some((name, standing)) = current.take() {
+                        cache.standings.insert(name, standing);
+                    }
+                    current = Some((value.to_string(), Standing::default()));
+                }
+                _ => {
+                    let (_, standing) = current.as_mut()?;
+                    match key {
+                        "removed_by" => standing.removed_by = Some(value.to_string()),
+                        "muted_by" => standing.muted = Some((value.to_string(), None)),
+                        "muted_until" => {
+                            let until = NaiveDateTime::parse_from_str(value, TIME_FORMAT).ok()?;
+                            standing.muted.as_mut()?.1 = Some(until);
+                        }
+                        "banned_by" => standing.banned_by = Some(value.to_string()),
+                        _ => {}
+                    }
+                }
+            }
+        }
+        if let Some((name, standing)) = current {
+            cache.standings.insert(name, standing);
+        }
+        Some(cache)
+    }
+
+    /// Writes the cache of `chatfile`.
+    pub fn save(&self, chatfile: &Path) -> Result<()> {
+        let mut content = format!("offset={}\ninode={}\n", self.offset, self.inode);
+        for (name, standing) in &self.standings {
+            content.push_str(&format!("name={name}\n"));
+            if let Some(by) = &standing.removed_by {
+                content.push_str(&format!("removed_by={by}\n"));
+            }
+            if let Some((by, until)) = &standing.muted {
+                content.push_str(&format!("muted_by={by}\n"));
+                if let Some(until) = until {
+                    content.push_str(&format!("muted_until={}\n", until.format(TIME_FORMAT)));
+                }
+            }
+            if let Some(by) = &standing.banned_by {
+                content.push_str(&format!("banned_by={by}\n"));
+            }
+        }
+        sidecar::write_atomic(&path(chatfile), &content)
+    }
+
+    /// Applies one line of the room. Renames carry a standing over, so
+    /// renaming doesn't escape a mute or ban.
+    pub fn apply(&mut self, message: Message) {
+        match message {
+            Message::Join { name, .. } => {
+                if let Some(standing) = self.standings.get_mut(&name) {
+                    standing.removed_by = None;
+                }
+                self.prune(&name);
+            }
+            Message::Rename { from, to, .. } => {
+                if let Some(standing) = self.standings.remove(&from) {
+                    self.standings.insert(to, standing);
+                }
+            }
+            Message::Moderation {
+                target, action, by, ..
+            } => {
+                let standing = self.standings.entry(target.clone()).or_default();
+                match action {
+                    Moderation::Kick => standing.removed_by = Some(by),
+                    Moderation::Mute { until } => standing.muted = Some((by, until)),
+                    Moderation::Unmute => standing.muted = None,
+                    Moderation::Ban => {
+                        standing.removed_by = Some(by.clone());
+                        standing.banned_by = Some(by);
+                    }
+                    Moderation::Unban => standing.banned_by = None,
+                }
+                self.prune(&target);
+            }
+            _ => {}
+        }
+    }
+
+    fn prune(&mut self, name: &str) {
+        if self.standings.get(name) == Some(&Standing::default()) {
+            self.standings.remove(name);
+        }
+    }
+
+    /// Returns the standing of `name`.
+    pub fn get(&self, name: &str) -> Standing {
+        self.standings.get(name).cloned().unwrap_or_default()
+    }
+}
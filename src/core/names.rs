@@ -1,7 +1,9 @@
 use rand::Rng;
 
+use super::acl::Acl;
 use super::chatfile::Chatfile;
 use super::error::{Error, Result};
+use super::session::Session;
 
 const ADJECTIVES: &[&str] = &[
     "swift", "bold", "calm", "keen", "sage", "wild", "bright", "dark", "quick", "slow",
@@ -23,7 +25,7 @@ pub fn generate(chatfile: &Chatfile) -> Result<String> {
 
         let name = format!("{adj}-{noun}-{suffix}");
 
-        if !chatfile.name_exists(&name)? {
+        if !is_taken(&name, chatfile)? {
             return Ok(name);
         }
     }
@@ -54,17 +56,26 @@ pub fn resolve_custom(name: &str, chatfile: &Chatfile) -> Result<String> {
     validate(name)?;
 
     // First try: exact match
-    if !chatfile.name_exists(name)? {
+    if !is_taken(name, chatfile)? {
         return Ok(name.to_string());
     }
 
     // Subsequent tries: append _N
     for i in 2..100 {
         let candidate = format!("{}_{}", name, i);
-        if !chatfile.name_exists(&candidate)? {
+        if !is_taken(&candidate, chatfile)? {
             return Ok(candidate);
         }
     }
 
     Err(Error::NameGenerationFailed(100))
 }
+
+/// A name is taken if it has spoken in the room, another session holds it,
+/// or it has a role in the room, which a newcomer must not inherit.
+/// Callers must hold the Chatfile lock until the new session is saved.
+pub(crate) fn is_taken(name: &str, chatfile: &Chatfile) -> Result<bool> {
+    Ok(chatfile.name_exists(name)?
+        || Session::is_registered(&chatfile.path, name)?
+        || Acl::load(&chatfile.path)?.is_some_and(|acl| acl.lists(name)))
+}
@@ -1,11 +1,21 @@
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
 
+use super::acl::{self, Acl, Role};
 use super::admin;
-use super::chatfile::Chatfile;
+use super::chain;
+use super::chatfile::{self, Chatfile};
 use super::clear;
 use super::error::{Error, Result};
+use super::message::{Message, Moderation};
 use super::names;
-use super::session::Session;
+use super::pins;
+use super::presence;
+use super::protect::{self, Protection};
+use super::rate::{self, RateLimit};
+use super::room::RosterEntry;
+use super::session::{Session, SessionEntry, SessionSelector};
+use super::thread;
 use crate::log;
 
 pub fn register(chatfile_path: impl AsRef<Path>, custom_name: Option<&str>) -> Result<Session> {
@@ -18,6 +28,10 @@ pub fn register(chatfile_path: impl AsRef<Path>, custom_name: Option<&str>) -> R
 
     let chatfile = Chatfile::open(&canonical)?;
 
+    // Hold the lock from name resolution until the session is saved, so two
+    // agents registering at once can't both claim the same name
+    let _lock = chatfile.lock()?;
+
     let name = match custom_name {
         Some(n) => {
             log::debug("ops", &format!("Resolving custom name: {}", n));
@@ -29,7 +43,9 @@ pub fn register(chatfile_path: impl AsRef<Path>, custom_name: Option<&str>) -> R
         }
     };
 
-    let session = Session::new(canonical, name.clone());
+    let mut session = Session::new(canonical, name.clone());
+    session.cursor = Some(chatfile.size()?);
+    session.inode = chatfile.inode()?;
     session.save()?;
 
     log::info(
@@ -39,16 +55,25 @@ pub fn register(chatfile_path: impl AsRef<Path>, custom_name: Option<&str>) -> R
     Ok(session)
 }
 
-pub fn join() -> Result<Session> {
-    let mut session = Session::load()?;
+pub fn join(selector: &SessionSelector) -> Result<Session> {
+    let mut session = Session::load(selector)?;
+    let chatfile = Chatfile::open(&session.chatfile)?;
+    let standing = chatfile.standing(&session.name)?;
 
-    if session.joined {
+    if let Some(by) = standing.banned_by {
+        return Err(Error::Banned(by));
+    }
+    // After a kick the session file still says joined, but the room doesn't
+    if session.joined && standing.removed_by.is_none() {
         log::warn("ops", &format!("Already joined as {}", session.name));
         return Err(Error::AlreadyJoined(session.name));
     }
 
-    let chatfile = Chatfile::open(&session.chatfile)?;
-    chatfile.announce_join(&session.name)?;
+    // Observers never write to the room, not even their arrival
+    if acl::role_of(&chatfile.path, &session.name)? != Some(Role::Observer) {
+        chatfile.announce_join(&session.name)?;
+    }
+    presence::touch(&chatfile, &session.name);
 
     session.joined = true;
     session.save()?;
@@ -57,8 +82,8 @@ pub fn join() -> Result<Session> {
     Ok(session)
 }
 
-pub fn leave() -> Result<Session> {
-    let mut session = Session::load()?;
+pub fn leave(selector: &SessionSelector) -> Result<Session> {
+    let mut session = Session::load(selector)?;
 
     if !session.joined {
         log::warn("ops", "Attempted to leave but not joined");
@@ -66,7 +91,8 @@ pub fn leave() -> Result<Session> {
     }
 
     let chatfile = Chatfile::open(&session.chatfile)?;
-    chatfile.announce_leave(&session.name)?;
+    announce_leave(&chatfile, &session.name)?;
+    presence::remove(&chatfile.path, &session.name)?;
 
     session.joined = false;
     session.save()?;
@@ -75,64 +101,776 @@ pub fn leave() -> Result<Session> {
     Ok(session)
 }
 
-pub fn send(message: &str) -> Result<()> {
-    let session = Session::load()?;
+/// Announces that `name` left, unless it was kicked or banned, which the
+/// room already records, or is an observer, which never writes to it.
+fn announce_leave(chatfile: &Chatfile, name: &str) -> Result<()> {
+    let removed = chatfile.standing(name)?.removed_by.is_some();
+    if !removed && acl::role_of(&chatfile.path, name)? != Some(Role::Observer) {
+        chatfile.announce_leave(name)?;
+    }
+    Ok(())
+}
+
+/// Returns the id of the session file at `path` (its file stem).
+fn session_id(path: &Path) -> String {
+    path.file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+/// Deletes the current session, leaving the room first if joined.
+pub fn unregister(selector: &SessionSelector) -> Result<Session> {
+    let path = Session::find_session_file(selector)?;
+    let session = Session::load_from(&path)?;
+
+    if session.joined {
+        match Chatfile::open(&session.chatfile) {
+            Ok(chatfile) => {
+                announce_leave(&chatfile, &session.name)?;
+                presence::remove(&chatfile.path, &session.name)?;
+            }
+            // Nobody to tell in a room that is gone
+            Err(Error::ChatfileNotFound(_)) => {}
+            Err(e) => return Err(e),
+        }
+    }
+
+    std::fs::remove_file(&path)?;
+    pins::replace_session(&session_id(&path), None)?;
+
+    log::info("ops", &format!("Unregistered {}", session.name));
+    Ok(session)
+}
+
+/// Renames the current session, announcing it in the room if joined.
+/// Unlike `register`, a taken name is an error rather than numbered.
+pub fn rename(selector: &SessionSelector, new_name: &str) -> Result<Session> {
+    names::validate(new_name)?;
+
+    let path = Session::find_session_file(selector)?;
+    let mut session = Session::load_from(&path)?;
+    let chatfile = Chatfile::open(&session.chatfile)?;
+
+    // Same as register: no one may claim the name until we have saved it
+    let lock = chatfile.lock()?;
+    if names::is_taken(new_name, &chatfile)? {
+        return Err(Error::NameTaken(new_name.to_string()));
+    }
+
+    let old_name = std::mem::replace(&mut session.name, new_name.to_string());
+    let new_path = session.path()?;
+    session.save_to(&new_path)?;
+    if new_path != path {
+        std::fs::remove_file(&path)?;
+        pins::replace_session(&session_id(&path), Some(&session_id(&new_path)))?;
+    }
+
+    // The role goes with the name, or renaming would shed restrictions
+    let acl = Acl::load(&chatfile.path)?;
+    if let Some(mut acl) = acl.clone() {
+        if acl.rename(&old_name, new_name) {
+            acl.save(&chatfile.path)?;
+        }
+    }
+    // The name is ours now. Appending takes the lock itself, and flock
+    // doesn't nest across descriptors
+    drop(lock);
+
+    if session.joined {
+        if acl.is_none_or(|acl| acl.role(&old_name) != Role::Observer) {
+            chatfile.announce_rename(&old_name, new_name)?;
+        }
+        presence::remove(&chatfile.path, &old_name)?;
+        presence::touch(&chatfile, new_name);
+    }
+
+    log::info("ops", &format!("Renamed {old_name} to {new_name}"));
+    Ok(session)
+}
+
+/// Why `gc_sessions` considers a session stale.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stale {
+    /// The Chatfile it is registered to no longer exists
+    MissingChatfile,
+    /// The session file has not changed for this long
+    Idle(Duration),
+}
+
+/// Removes sessions whose Chatfile is gone and, with `idle`, sessions whose
+/// file has not been touched for longer than that. Joined sessions removed
+/// for idleness leave their room. With `dry_run` nothing is changed.
+pub fn gc_sessions(idle: Option<Duration>, dry_run: bool) -> Result<Vec<(SessionEntry, Stale)>> {
+    let now = SystemTime::now();
+    let mut stale = Vec::new();
+
+    for entry in Session::entries()? {
+        let reason = if !entry.session.chatfile.exists() {
+            Stale::MissingChatfile
+        } else {
+            let age = std::fs::metadata(&entry.path)
+                .and_then(|m| m.modified())
+                .ok()
+                .and_then(|modified| now.duration_since(modified).ok());
+            match (idle, age) {
+                (Some(limit), Some(age)) if age > limit => Stale::Idle(age),
+                _ => continue,
+            }
+        };
+
+        if !dry_run {
+            if entry.session.joined && reason != Stale::MissingChatfile {
+                let chatfile = Chatfile::open(&entry.session.chatfile)?;
+                announce_leave(&chatfile, &entry.session.name)?;
+                presence::remove(&entry.session.chatfile, &entry.session.name)?;
+            }
+            std::fs::remove_file(&entry.path)?;
+            pins::replace_session(&entry.id, None)?;
+            log::info("ops", &format!("Removed stale session {}", entry.id));
+        }
+        stale.push((entry, reason));
+    }
+
+    Ok(stale)
+}
+
+pub fn send(selector: &SessionSelector, message: &str) -> Result<()> {
+    let mut session = Session::load(selector)?;
 
     if !session.joined {
         return Err(Error::NotJoined);
     }
 
     let chatfile = Chatfile::open(&session.chatfile)?;
+    check_can_send(&chatfile, &mut session)?;
     log::debug("ops", &format!("Sending message: '{}'", message));
-    chatfile.send(&session.name, message)
+    chatfile.send(&session.name, message)?;
+    presence::touch(&chatfile, &session.name);
+    Ok(())
 }
 
-pub fn admin_send(message: &str) -> Result<()> {
-    let session = Session::load()?;
-    let chatfile_path = session.chatfile.to_string_lossy();
+/// Sends `message` as a reply to the message with id `reply_to`.
+pub fn reply(selector: &SessionSelector, reply_to: u64, message: &str) -> Result<()> {
+    let mut session = Session::load(selector)?;
+
+    if !session.joined {
+        return Err(Error::NotJoined);
+    }
+
+    let chatfile = Chatfile::open(&session.chatfile)?;
+    check_can_send(&chatfile, &mut session)?;
+    log::debug("ops", &format!("Replying to #{reply_to}: '{}'", message));
+    chatfile.send_reply(&session.name, reply_to, message)?;
+    presence::touch(&chatfile, &session.name);
+    Ok(())
+}
+
+/// Fails unless `session` may send in `chatfile`: not after being kicked
+/// or banned, which also marks the session as no longer joined, nor while
+/// muted or an observer.
+fn check_can_send(chatfile: &Chatfile, session: &mut Session) -> Result<()> {
+    let standing = chatfile.standing(&session.name)?;
+
+    if let Some(by) = standing.removed_by {
+        session.joined = false;
+        session.save()?;
+        presence::remove(&chatfile.path, &session.name)?;
+        return Err(match standing.banned_by {
+            Some(by) => Error::Banned(by),
+            None => Error::Kicked(by),
+        });
+    }
+    if let Some((by, until)) = standing.muted_at(chatfile::now()) {
+        return Err(Error::Muted(match until {
+            Some(until) => format!("{by} until {}", until.format("%F %T")),
+            None => by.clone(),
+        }));
+    }
+
+    match acl::role_of(&chatfile.path, &session.name)? {
+        Some(role) if !role.can_send() => Err(Error::PermissionDenied(format!(
+            "{} is an observer in this room",
+            session.name
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// Checks that `session` may act as admin in its room: as moderator or
+/// owner in a room with an ACL, whose role is returned, or with a
+/// `.cf_admin` file otherwise.
+fn check_moderator(session: &Session) -> Result<Option<Role>> {
+    match acl::role_of(&session.chatfile, &session.name)? {
+        Some(role) if role.can_admin_send() => Ok(Some(role)),
+        Some(role) => Err(Error::PermissionDenied(format!(
+            "{} ({role}) is not a moderator or owner",
+            session.name
+        ))),
+        None if admin::is_admin() => Ok(None),
+        None => Err(Error::NotAdmin),
+    }
+}
+
+/// Sends an admin message. In a room with an ACL this needs the moderator
+/// or owner role, otherwise a `.cf_admin` file.
+pub fn admin_send(selector: &SessionSelector, message: &str) -> Result<()> {
+    let session = Session::load(selector)?;
+    check_moderator(&session)?;
     log::info("ops", &format!("Admin sending: '{}'", message));
-    admin::admin_send(&chatfile_path, message)
+
+    // In rooms with roles the prefix may still come from a .cf_admin file
+    let config = admin::AdminConfig::load().unwrap_or_default();
+    admin::send_as(&config, &session.chatfile.to_string_lossy(), message)
 }
 
-pub fn await_message() -> Result<String> {
-    let session = Session::load()?;
+/// Appends a moderation record against `target` to the current session's
+/// room. Needs the same rights as `admin_send`, and in a room with an ACL
+/// nobody may moderate a higher role than their own.
+pub fn moderate(selector: &SessionSelector, target: &str, action: Moderation) -> Result<()> {
+    names::validate(target)?;
+    let session = Session::load(selector)?;
+    let role = check_moderator(&session)?;
+    let chatfile = Chatfile::open(&session.chatfile)?;
 
-    if !session.joined {
-        return Err(Error::NotJoined);
+    if let Some(role) = role {
+        let target_role = acl::role_of(&chatfile.path, target)?.unwrap_or(Role::Member);
+        if target_role > role {
+            return Err(Error::PermissionDenied(format!(
+                "{target} ({target_role}) outranks {} ({role})",
+                session.name
+            )));
+        }
     }
 
+    chatfile.announce_moderation(target, action, &session.name)?;
+    if matches!(action, Moderation::Kick | Moderation::Ban) {
+        presence::remove(&chatfile.path, target)?;
+    }
+
+    log::info(
+        "ops",
+        &format!("{} {target} in {:?}", action.verb(), chatfile.path),
+    );
+    Ok(())
+}
+
+/// Mutes `target` for `duration`, or until unmuted.
+pub fn mute(selector: &SessionSelector, target: &str, duration: Option<Duration>) -> Result<()> {
+    let until = duration.and_then(|d| {
+        let d = chrono::TimeDelta::from_std(d).ok()?;
+        chatfile::now().checked_add_signed(d)
+    });
+    moderate(selector, target, Moderation::Mute { until })
+}
+
+/// Lists the roles granted in the current session's room.
+pub fn roles(selector: &SessionSelector) -> Result<Vec<(String, Role)>> {
+    let session = Session::load(selector)?;
+    Ok(Acl::load(&session.chatfile)?
+        .map(|acl| acl.entries().to_vec())
+        .unwrap_or_default())
+}
+
+/// Opens the current session's room for a role change, after checking the
+/// session may make one: owners may, and while the room has
+/// no owner, so may holders of a `.cf_admin` file.
+fn manage_roles(selector: &SessionSelector) -> Result<Chatfile> {
+    let session = Session::load(selector)?;
     let chatfile = Chatfile::open(&session.chatfile)?;
+    let acl = Acl::load(&chatfile.path)?.unwrap_or_default();
+
+    if acl.has_owner() {
+        if acl.role(&session.name) != Role::Owner {
+            return Err(Error::PermissionDenied(format!(
+                "only owners can change roles, not {} ({})",
+                session.name,
+                acl.role(&session.name)
+            )));
+        }
+    } else if !admin::is_admin() {
+        return Err(Error::NotAdmin);
+    }
+    Ok(chatfile)
+}
+
+/// Gives `name` `role` in the current session's room, creating its ACL if
+/// needed.
+pub fn grant_role(selector: &SessionSelector, name: &str, role: Role) -> Result<()> {
+    names::validate(name)?;
+    let chatfile = manage_roles(selector)?;
+
+    // Reload under the lock so concurrent changes aren't lost
+    let _lock = chatfile.lock()?;
+    let mut acl = Acl::load(&chatfile.path)?.unwrap_or_default();
+    acl.grant(name, role);
+    acl.save(&chatfile.path)?;
+
+    log::info("ops", &format!("Granted {role} to {name}"));
+    Ok(())
+}
+
+/// Takes back the role of `name`, making it a member again. Returns whether
+/// it had one. Revoking the last role removes the ACL.
+pub fn revoke_role(selector: &SessionSelector, name: &str) -> Result<bool> {
+    let chatfile = manage_roles(selector)?;
+
+    let _lock = chatfile.lock()?;
+    let Some(mut acl) = Acl::load(&chatfile.path)? else {
+        return Ok(false);
+    };
+    if !acl.revoke(name) {
+        return Ok(false);
+    }
+    acl.save(&chatfile.path)?;
+
+    log::info("ops", &format!("Revoked the role of {name}"));
+    Ok(true)
+}
 
-    // Check if last line is a message from another user (not system, not self)
-    if let Some(last) = chatfile.last_line()? {
-        if let Some(sender) = Chatfile::get_sender(&last) {
-            if sender != session.name {
-                return Ok(last);
+/// Narrows which messages wake `await`. Messages that don't match are
+/// still consumed, i.e. the session cursor moves past them.
+#[derive(Debug, Clone, Default)]
+pub struct AwaitFilter {
+    /// Only messages that @mention the session name (or `@all`)
+    pub mentions: bool,
+    /// Only messages from this sender
+    pub from: Option<String>,
+}
+
+impl AwaitFilter {
+    fn matches(&self, line: &str, own_name: &str) -> bool {
+        // System lines and own messages never match
+        let Some(sender) = Chatfile::get_sender(line) else {
+            return false;
+        };
+        if sender == own_name {
+            return false;
+        }
+        if self.from.as_deref().is_some_and(|from| from != sender) {
+            return false;
+        }
+        !self.mentions || Message::parse(line).mentions_name(own_name)
+    }
+}
+
+/// Waits until at least one message from another agent has been appended
+/// since the session cursor, then returns every such message in order.
+/// System lines and own messages are consumed without being returned.
+pub fn await_message(selector: &SessionSelector) -> Result<Vec<String>> {
+    await_filtered(selector, &AwaitFilter::default(), None)
+}
+
+/// Like `await_message`, but fails with `Error::Timeout` after `timeout`.
+pub fn await_message_timeout(selector: &SessionSelector, timeout: Duration) -> Result<Vec<String>> {
+    await_filtered(selector, &AwaitFilter::default(), Some(timeout))
+}
+
+/// Non-blocking `await_message`: returns the unseen messages from other
+/// agents, or an empty list if there are none.
+pub fn poll_messages(selector: &SessionSelector) -> Result<Vec<String>> {
+    poll_filtered(selector, &AwaitFilter::default())
+}
+
+/// `await_message` that only wakes for messages matching `filter`,
+/// optionally giving up with `Error::Timeout` after `timeout`.
+pub fn await_filtered(
+    selector: &SessionSelector,
+    filter: &AwaitFilter,
+    timeout: Option<Duration>,
+) -> Result<Vec<String>> {
+    // A timeout too far out to represent is as good as none
+    let deadline = timeout.and_then(|t| Instant::now().checked_add(t));
+    let (mut session, chatfile, mut cursor) = load_await_session(selector)?;
+    let inode = check_cursor(&mut session, &chatfile, cursor)?;
+    let member = [(&chatfile, session.name.as_str())];
+
+    loop {
+        let watched = presence::keep_alive(&member, || chatfile.watch_from(cursor, deadline));
+        let (lines, next) = match watched {
+            Err(Error::RoomRotated(path)) => {
+                reset_cursor(&mut session, &chatfile)?;
+                return Err(Error::RoomRotated(path));
             }
+            other => other?,
+        };
+        cursor = next;
+
+        let messages = incoming(lines, &session.name, filter);
+        if !messages.is_empty() {
+            session.cursor = Some(cursor);
+            session.inode = inode;
+            session.save()?;
+            return Ok(messages);
         }
-        // If get_sender returned None, it's a system message - wait for real message
+        // Nothing that matches - keep waiting
+    }
+}
+
+/// `poll_messages` restricted to messages matching `filter`.
+pub fn poll_filtered(selector: &SessionSelector, filter: &AwaitFilter) -> Result<Vec<String>> {
+    let (mut session, chatfile, cursor) = load_await_session(selector)?;
+    let inode = check_cursor(&mut session, &chatfile, cursor)?;
+
+    let (lines, next) = chatfile.read_from(cursor)?;
+    session.cursor = Some(next);
+    session.inode = inode;
+    session.save()?;
+
+    Ok(incoming(lines, &session.name, filter))
+}
+
+fn load_await_session(selector: &SessionSelector) -> Result<(Session, Chatfile, u64)> {
+    let session = Session::load(selector)?;
+
+    if !session.joined {
+        return Err(Error::NotJoined);
+    }
+
+    let chatfile = Chatfile::open(&session.chatfile)?;
+    presence::touch(&chatfile, &session.name);
+    let cursor = match session.cursor {
+        Some(c) => c,
+        None => chatfile.size()?,
+    };
+    Ok((session, chatfile, cursor))
+}
+
+/// Fails with `Error::RoomRotated` if the Chatfile was replaced since the
+/// cursor was saved or shrank below it, resetting the cursor so the next
+/// read starts at the top of the new file. Returns the inode the cursor
+/// points into, to be saved along with it.
+fn check_cursor(session: &mut Session, chatfile: &Chatfile, cursor: u64) -> Result<Option<u64>> {
+    let inode = chatfile.inode()?;
+    let replaced = session.inode.is_some() && session.inode != inode;
+    if !replaced && cursor <= chatfile.size()? {
+        return Ok(inode);
+    }
+    reset_cursor(session, chatfile)?;
+    Err(Error::RoomRotated(chatfile.path.clone()))
+}
+
+/// Points the cursor at the top of the file now at the Chatfile's path:
+/// after a rotation, everything in the new file is unseen.
+fn reset_cursor(session: &mut Session, chatfile: &Chatfile) -> Result<()> {
+    session.cursor = Some(0);
+    session.inode = chatfile.inode().ok().flatten();
+    session.save()
+}
+
+/// Keeps only messages from other agents that match `filter`.
+fn incoming(lines: Vec<String>, own_name: &str, filter: &AwaitFilter) -> Vec<String> {
+    lines
+        .into_iter()
+        .filter(|line| filter.matches(line, own_name))
+        .collect()
+}
+
+/// New messages from one of the rooms passed to `await_rooms`.
+#[derive(Debug)]
+pub struct RoomMessages {
+    pub room: PathBuf,
+    pub lines: Vec<String>,
+}
+
+/// A room being awaited, with the session that tracks its cursor if any.
+struct AwaitedRoom {
+    chatfile: Chatfile,
+    cursor: u64,
+    inode: Option<u64>,
+    session: Option<Session>,
+}
+
+/// Waits on several rooms at once and returns the messages matching
+/// `filter` from whichever room receives them first.
+///
+/// Each room uses the session registered to it under the current session's
+/// name, so its cursor advances as with `await_message`. Rooms without such
+/// a session are watched from their current end.
+pub fn await_rooms(
+    selector: &SessionSelector,
+    rooms: &[impl AsRef<Path>],
+    filter: &AwaitFilter,
+    timeout: Option<Duration>,
+) -> Result<RoomMessages> {
+    // A timeout too far out to represent is as good as none
+    let deadline = timeout.and_then(|t| Instant::now().checked_add(t));
+    let current = Session::load(selector)?;
+    let name = current.name.clone();
+
+    let mut awaited = Vec::with_capacity(rooms.len());
+    for room in rooms {
+        let chatfile = Chatfile::open(room)?;
+        let canonical = std::fs::canonicalize(&chatfile.path)?;
+
+        let mut session = if canonical == current.chatfile {
+            Some(current.clone())
+        } else {
+            Session::find_registered(&canonical, &name)?.map(|(s, _)| s)
+        };
+        let cursor = match session.as_ref().and_then(|s| s.cursor) {
+            Some(c) => c,
+            None => chatfile.size()?,
+        };
+        let inode = match &mut session {
+            Some(session) => check_cursor(session, &chatfile, cursor)?,
+            None => chatfile.inode()?,
+        };
+
+        awaited.push(AwaitedRoom {
+            chatfile,
+            cursor,
+            inode,
+            session,
+        });
+    }
+
+    // Heartbeats for every room we are joined to while waiting
+    let joined: Vec<_> = awaited
+        .iter()
+        .filter(|r| r.session.as_ref().is_some_and(|s| s.joined))
+        .map(|r| r.chatfile.clone())
+        .collect();
+    let members: Vec<_> = joined.iter().map(|c| (c, name.as_str())).collect();
+    for (chatfile, name) in &members {
+        presence::touch(chatfile, name);
     }
 
-    // Wait for new message, but filter out system messages
     loop {
-        let line = chatfile.watch()?;
-        if let Some(sender) = Chatfile::get_sender(&line) {
-            if sender != session.name {
-                return Ok(line);
+        let watched: Vec<_> = awaited.iter().map(|r| (&r.chatfile, r.cursor)).collect();
+        let result = presence::keep_alive(&members, || Chatfile::watch_many(&watched, deadline));
+        let (i, lines, next) = match result {
+            Err(Error::RoomRotated(path)) => {
+                if let Some(room) = awaited.iter_mut().find(|r| r.chatfile.path == path) {
+                    if let Some(session) = &mut room.session {
+                        reset_cursor(session, &room.chatfile)?;
+                    }
+                }
+                return Err(Error::RoomRotated(path));
+            }
+            other => other?,
+        };
+
+        let room = &mut awaited[i];
+        room.cursor = next;
+
+        let messages = incoming(lines, &name, filter);
+        if !messages.is_empty() {
+            if let Some(session) = &mut room.session {
+                session.cursor = Some(next);
+                session.inode = room.inode;
+                session.save()?;
             }
+            return Ok(RoomMessages {
+                room: room.chatfile.path.clone(),
+                lines: messages,
+            });
+        }
+    }
+}
+
+pub fn await_parsed(selector: &SessionSelector) -> Result<Vec<Message>> {
+    let lines = await_message(selector)?;
+    Ok(lines.iter().map(|l| Message::parse(l)).collect())
+}
+
+/// Refreshes the current session's heartbeat, for agents busy with long
+/// work between `cf` calls.
+pub fn heartbeat(selector: &SessionSelector) -> Result<Session> {
+    let session = Session::load(selector)?;
+    if !session.joined {
+        return Err(Error::NotJoined);
+    }
+    let chatfile = Chatfile::open(&session.chatfile)?;
+    presence::heartbeat(&chatfile, &session.name)?;
+    Ok(session)
+}
+
+/// Opens `room`, or the current session's room.
+fn open_room(selector: &SessionSelector, room: Option<&Path>) -> Result<Chatfile> {
+    match room {
+        Some(room) => Chatfile::open(room),
+        None => Chatfile::open(Session::load(selector)?.chatfile),
+    }
+}
+
+/// Lists who is present in `room`, or the current session's room: everyone
+/// with a heartbeat, plus roster members without one, who count as stale.
+pub fn who(selector: &SessionSelector, room: Option<&Path>) -> Result<Vec<presence::Member>> {
+    let chatfile = open_room(selector, room)?;
+    let mut members = presence::members(&chatfile.path)?;
+
+    for entry in chatfile.members()? {
+        if !members.iter().any(|m| m.name == entry.name) {
+            members.push(presence::Member {
+                name: entry.name,
+                last_seen: None,
+                status: presence::Status::Stale,
+            });
         }
-        // System message or own message - keep waiting
+    }
+    Ok(members)
+}
+
+/// Checks `room`, or the current session's room: fails with
+/// `Error::Tampered` if its snapshot recorded a replacement or truncation
+/// that wasn't accepted, or naming the first line where the hash chain
+/// breaks. Returns the number of lines checked along the chain, or `None`
+/// for rooms without one, which only the snapshot protects.
+pub fn verify(selector: &SessionSelector, room: Option<&Path>) -> Result<Option<u64>> {
+    let chatfile = open_room(selector, room)?;
+    if let Some(violation) = protect::observe(&chatfile.path) {
+        return Err(Error::Tampered(violation));
+    }
+    if !chatfile.options.hash_chain {
+        return Ok(None);
+    }
+
+    match chain::verify(&chatfile.path)? {
+        (lines, None) => Ok(Some(lines)),
+        (_, Some(broken)) => Err(Error::Tampered(broken.to_string())),
     }
 }
 
-pub fn read(n: usize) -> Result<Vec<String>> {
-    let session = Session::load()?;
+/// Accepts `room`, or the current session's room, as it is now after a
+/// replacement or truncation. Returns the violation that was forgotten.
+pub fn accept(selector: &SessionSelector, room: Option<&Path>) -> Result<Option<String>> {
+    let chatfile = open_room(selector, room)?;
+    let violation = protect::accept(&chatfile.path)?;
+    if let Some(violation) = &violation {
+        log::info(
+            "ops",
+            &format!("Accepted {}: {violation}", chatfile.path.display()),
+        );
+    }
+    Ok(violation)
+}
+
+/// Returns the roster of `room`, or the current session's room, as
+/// recorded by its join and leave lines.
+pub fn members(selector: &SessionSelector, room: Option<&Path>) -> Result<Vec<RosterEntry>> {
+    open_room(selector, room)?.members()
+}
+
+pub fn read(selector: &SessionSelector, n: usize) -> Result<Vec<String>> {
+    let session = Session::load(selector)?;
     let chatfile = Chatfile::open(&session.chatfile)?;
     chatfile.read_last(n)
 }
 
-pub fn status() -> Result<Session> {
-    Session::load()
+/// Returns every line appended since the session cursor and advances it,
+/// so each line is delivered exactly once.
+pub fn read_new(selector: &SessionSelector) -> Result<Vec<String>> {
+    let mut session = Session::load(selector)?;
+    let chatfile = Chatfile::open(&session.chatfile)?;
+
+    let cursor = match session.cursor {
+        Some(c) => c,
+        None => chatfile.size()?,
+    };
+    let inode = check_cursor(&mut session, &chatfile, cursor)?;
+    let (lines, next) = chatfile.read_from(cursor)?;
+    if session.joined {
+        presence::touch(&chatfile, &session.name);
+    }
+
+    session.cursor = Some(next);
+    session.inode = inode;
+    session.save()?;
+    Ok(lines)
+}
+
+/// Returns the last `n` lines paired with their message ids.
+pub fn read_numbered(selector: &SessionSelector, n: usize) -> Result<Vec<(u64, String)>> {
+    let session = Session::load(selector)?;
+    let chatfile = Chatfile::open(&session.chatfile)?;
+    chatfile.read_last_numbered(n)
+}
+
+/// Returns the thread containing message `id`, paired with message ids.
+pub fn read_thread(selector: &SessionSelector, id: u64) -> Result<Vec<(u64, String)>> {
+    let session = Session::load(selector)?;
+    let chatfile = Chatfile::open(&session.chatfile)?;
+    thread::thread(&chatfile, id)
+}
+
+pub fn read_parsed(selector: &SessionSelector, n: usize) -> Result<Vec<Message>> {
+    let session = Session::load(selector)?;
+    let chatfile = Chatfile::open(&session.chatfile)?;
+    chatfile.read_messages(n)
+}
+
+/// A room's limits, and how much of its rate limit a session has used in
+/// the current window.
+#[derive(Debug, Clone)]
+pub struct Limits {
+    pub rate_limit: Option<RateLimit>,
+    pub used: u32,
+    pub max_length: Option<usize>,
+}
+
+/// What the current session's room says about the session.
+#[derive(Debug, Clone)]
+pub struct RoomStatus {
+    /// `None` if the room has no ACL
+    pub role: Option<Role>,
+    pub limits: Limits,
+    pub protection: Protection,
+    /// Replacement or truncation recorded since the room was last accepted
+    pub tampered: Option<String>,
+}
+
+/// Returns the current session, and what its room says about it. Failing
+/// to read the room, e.g. because it was deleted, doesn't fail the status:
+/// that is when the session details are needed most.
+pub fn status(selector: &SessionSelector) -> Result<(Session, Result<RoomStatus>)> {
+    let session = Session::load(selector)?;
+    let room = room_status(&session);
+    Ok((session, room))
+}
+
+fn room_status(session: &Session) -> Result<RoomStatus> {
+    let chatfile = Chatfile::open(&session.chatfile)?;
+    let options = &chatfile.options;
+
+    let used = match options.rate_limit {
+        Some(limit) => rate::used(&chatfile.path, &session.name, limit)?,
+        None => 0,
+    };
+    Ok(RoomStatus {
+        role: acl::role_of(&chatfile.path, &session.name)?,
+        limits: Limits {
+            rate_limit: options.rate_limit,
+            used,
+            max_length: options.max_length,
+        },
+        protection: protect::level(&chatfile.path),
+        tampered: protect::observe(&chatfile.path),
+    })
+}
+
+/// Lists every registered session, with the path of the one commands
+/// would currently use, if any.
+pub fn sessions(selector: &SessionSelector) -> Result<(Vec<SessionEntry>, Option<PathBuf>)> {
+    let entries = Session::entries()?;
+    let active = Session::find_session_file(selector).ok();
+    Ok((entries, active))
+}
+
+/// Pins the session matching `selector` to the working directory and
+/// everything below it.
+pub fn use_session(selector: &SessionSelector) -> Result<SessionEntry> {
+    let entry = Session::resolve(selector)?;
+    pins::pin(&std::env::current_dir()?, &entry.id)?;
+    log::info(
+        "ops",
+        &format!("Pinned session {} ({})", entry.session.name, entry.id),
+    );
+    Ok(entry)
+}
+
+/// Removes the session pin on the working directory.
+pub fn unuse_session() -> Result<bool> {
+    pins::unpin(&std::env::current_dir()?)
 }
 
 pub fn list_clearable_files(sessions_only: bool) -> Result<clear::ClearableFiles> {
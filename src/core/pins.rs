@@ -0,0 +1,101 @@
+//! Per-directory session pins set by `cf use`.
+//!
+//! Pins live in a `pins` file in the sessions directory, one
+//! `<directory>\t<session id>` line each. A pin applies to its directory
+//! and everything below it; the nearest pinned ancestor wins.
+
+use std::path::{Path, PathBuf};
+
+use super::dirs;
+use super::error::Result;
+
+/// Returns the path of the pins file.
+pub fn path() -> Result<PathBuf> {
+    Ok(dirs::sessions_dir()?.join("pins"))
+}
+
+fn load() -> Result<Vec<(PathBuf, String)>> {
+    let content = match std::fs::read_to_string(path()?) {
+        Ok(c) => c,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+
+    Ok(content
+        .lines()
+        .filter_map(|line| line.rsplit_once('\t'))
+        .map(|(dir, id)| (PathBuf::from(dir), id.to_string()))
+        .collect())
+}
+
+fn store(pins: &[(PathBuf, String)]) -> Result<()> {
+    let path = path()?;
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    let content: String = pins
+        .iter()
+        .map(|(dir, id)| format!("{}\t{id}\n", dir.display()))
+        .collect();
+    std::fs::write(path, content)?;
+    Ok(())
+}
+
+fn canonical(dir: &Path) -> PathBuf {
+    std::fs::canonicalize(dir).unwrap_or_else(|_| dir.to_path_buf())
+}
+
+/// Pins session `id` to `dir`, replacing any previous pin there.
+pub fn pin(dir: &Path, id: &str) -> Result<()> {
+    let dir = canonical(dir);
+    let mut pins = load()?;
+    pins.retain(|(d, _)| *d != dir);
+    pins.push((dir, id.to_string()));
+    store(&pins)
+}
+
+/// Removes the pin on `dir`. Returns whether there was one.
+pub fn unpin(dir: &Path) -> Result<bool> {
+    let dir = canonical(dir);
+    let mut pins = load()?;
+    let before = pins.len();
+    pins.retain(|(d, _)| *d != dir);
+    if pins.len() == before {
+        return Ok(false);
+    }
+    store(&pins)?;
+    Ok(true)
+}
+
+/// Moves every pin on session `id` to `new_id`, or removes them for `None`.
+pub fn replace_session(id: &str, new_id: Option<&str>) -> Result<()> {
+    let mut pins = load()?;
+    if !pins.iter().any(|(_, pinned)| pinned == id) {
+        return Ok(());
+    }
+
+    match new_id {
+        Some(new_id) => pins
+            .iter_mut()
+            .filter(|(_, pinned)| pinned == id)
+            .for_each(|(_, pinned)| *pinned = new_id.to_string()),
+        None => pins.retain(|(_, pinned)| pinned != id),
+    }
+    store(&pins)
+}
+
+/// Returns the session id pinned to `dir` or its nearest pinned ancestor.
+pub fn pinned(dir: &Path) -> Result<Option<String>> {
+    let pins = load()?;
+    if pins.is_empty() {
+        return Ok(None);
+    }
+
+    let dir = canonical(dir);
+    Ok(dir.ancestors().find_map(|ancestor| {
+        pins.iter()
+            .find(|(d, _)| d == ancestor)
+            .map(|(_, id)| id.clone())
+    }))
+}
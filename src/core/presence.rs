@@ -0,0 +1,173 @@
+//! Presence: which joined agents are still alive.
+//!
+//! A `[name joined]` line stays in the Chatfile after its agent crashes, so
+//! each joined agent also keeps a heartbeat file next to the room, in
+//! `.<chatfile name>.presence/`. Sending, awaiting, `read --new` and
+//! `cf heartbeat` refresh it, and a blocking `cf await` refreshes it every
+//! `HEARTBEAT_INTERVAL`. Leaving removes it. Because it lives beside the
+//! Chatfile, agents on other machines sharing the directory see it too.
+
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::chatfile::Chatfile;
+use super::error::Result;
+use super::sidecar;
+use crate::log;
+
+/// How often a waiting `cf await` refreshes its heartbeat.
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(60);
+/// Members silent for longer than this are reported as idle.
+pub const IDLE_AFTER: Duration = Duration::from_secs(5 * 60);
+/// Members silent for longer than this are presumed gone.
+pub const STALE_AFTER: Duration = Duration::from_secs(30 * 60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    /// Heartbeat within `IDLE_AFTER`
+    Joined,
+    /// Heartbeat within `STALE_AFTER`
+    Idle,
+    /// No heartbeat for longer than `STALE_AFTER`; the agent likely died
+    /// without leaving
+    Stale,
+}
+
+impl Status {
+    fn from_age(age: Duration) -> Self {
+        if age <= IDLE_AFTER {
+            Self::Joined
+        } else if age <= STALE_AFTER {
+            Self::Idle
+        } else {
+            Self::Stale
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Joined => "joined",
+            Self::Idle => "idle",
+            Self::Stale => "stale",
+        }
+    }
+}
+
+/// A joined agent and when it was last seen.
+#[derive(Debug, Clone)]
+pub struct Member {
+    pub name: String,
+    /// `None` for members on the roster that never sent a heartbeat
+    pub last_seen: Option<SystemTime>,
+    pub status: Status,
+}
+
+/// Returns the presence directory for `chatfile`.
+pub fn dir(chatfile: &Path) -> PathBuf {
+    sidecar::path(chatfile, "presence")
+}
+
+/// Encodes a name as a file name; anything but `[A-Za-z0-9._-]` becomes
+/// `%XX`.
+pub(crate) fn file_name(name: &str) -> String {
+    let mut out = String::with_capacity(name.len());
+    for b in name.bytes() {
+        if b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.') {
+            out.push(b as char);
+        } else {
+            out.push_str(&format!("%{b:02X}"));
+        }
+    }
+    out
+}
+
+/// Records that `name` is alive in `chatfile` now.
+pub fn heartbeat(chatfile: &Chatfile, name: &str) -> Result<()> {
+    let dir = dir(&chatfile.path);
+    std::fs::create_dir_all(&dir)?;
+
+    let seen = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    sidecar::write_atomic(
+        &dir.join(file_name(name)),
+        &format!("name={name}\nseen={seen}\n"),
+    )
+}
+
+/// Like `heartbeat`, but only logs failures: presence is advisory and must
+/// not fail the command that refreshes it.
+pub fn touch(chatfile: &Chatfile, name: &str) {
+    if let Err(e) = heartbeat(chatfile, name) {
+        log::warn("presence", &format!("Heartbeat for {name} failed: {e}"));
+    }
+}
+
+/// Removes the heartbeat of `name`, e.g. when it leaves.
+pub fn remove(chatfile: &Path, name: &str) -> Result<()> {
+    match std::fs::remove_file(dir(chatfile).join(file_name(name))) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+/// Runs `wait`, refreshing the heartbeats of `members` every
+/// `HEARTBEAT_INTERVAL` until it returns.
+pub fn keep_alive<T>(members: &[(&Chatfile, &str)], wait: impl FnOnce() -> T) -> T {
+    if members.is_empty() {
+        return wait();
+    }
+
+    let (done, stopped) = mpsc::channel::<()>();
+    std::thread::scope(|s| {
+        s.spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(HEARTBEAT_INTERVAL) {
+                for (chatfile, name) in members {
+                    touch(chatfile, name);
+                }
+            }
+        });
+        let result = wait();
+        drop(done);
+        result
+    })
+}
+
+/// Lists everyone with a heartbeat in `chatfile`, most recently seen first.
+/// See `ops::who` for the view that includes the Chatfile's roster.
+pub fn members(chatfile: &Path) -> Result<Vec<Member>> {
+    let entries = match std::fs::read_dir(dir(chatfile)) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+
+    let now = SystemTime::now();
+    let mut members: Vec<_> = entries
+        .flatten()
+        // Skip heartbeats still being written
+        .filter(|entry| !entry.file_name().to_string_lossy().starts_with('.'))
+        .filter_map(|entry| {
+            let content = std::fs::read_to_string(entry.path()).ok()?;
+            let field = |key: &str| {
+                content
+                    .lines()
+                    .find_map(|l| l.strip_prefix(key)?.strip_prefix('='))
+            };
+            let name = field("name")?.to_string();
+            let seen: u64 = field("seen")?.parse().ok()?;
+            let last_seen = UNIX_EPOCH + Duration::from_secs(seen);
+            let age = now.duration_since(last_seen).unwrap_or_default();
+            Some(Member {
+                name,
+                last_seen: Some(last_seen),
+                status: Status::from_age(age),
+            })
+        })
+        .collect();
+
+    members.sort_by_key(|m| std::cmp::Reverse(m.last_seen));
+    Ok(members)
+}
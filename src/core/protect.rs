@@ -0,0 +1,306 @@
+//! Append-only protection for Chatfiles, without sudo.
+//!
+//! On Linux, when the process holds `CAP_LINUX_IMMUTABLE`, new rooms get
+//! the kernel append-only flag (what `chattr +a` sets) through the
+//! `FS_IOC_SETFLAGS` ioctl. Everywhere else, and on filesystems without
+//! the flag, protection falls back to snapshots: the inode and size of
+//! every room are kept next to it, in `.<chatfile name>.snapshot`, and
+//! every command that opens the room, and every append, checks that it
+//! was neither replaced nor truncated since. A violation is recorded there until someone accepts the room
+//! again with `cf verify --accept`; `cf status` and `cf verify` report it.
+//! Snapshots can't see edits that keep the size; see `chain` for those.
+
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::{Read, Seek, Write};
+use std::path::{Path, PathBuf};
+
+use super::error::{Error, Result};
+use super::lock::FileLock;
+use super::sidecar;
+use crate::log;
+
+/// How a room is protected against rewrites.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protection {
+    /// The kernel refuses anything but appends
+    AppendOnly,
+    /// Replacing or truncating the room is recorded by the next command
+    Snapshot,
+}
+
+impl Protection {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::AppendOnly => "append-only",
+            Self::Snapshot => "snapshot",
+        }
+    }
+}
+
+impl fmt::Display for Protection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod flags {
+    use std::fs::File;
+    use std::os::fd::AsRawFd;
+    use std::path::Path;
+
+    /// `FS_APPEND_FL` from `linux/fs.h`; libc doesn't export it.
+    const FS_APPEND_FL: libc::c_int = 0x20;
+    /// Bit of `CAP_LINUX_IMMUTABLE` in the capability sets.
+    const CAP_LINUX_IMMUTABLE: u32 = 9;
+
+    /// Checks the effective capabilities in `/proc/self/status`.
+    pub fn has_capability() -> bool {
+        let Ok(status) = std::fs::read_to_string("/proc/self/status") else {
+            return false;
+        };
+        status
+            .lines()
+            .find_map(|l| l.strip_prefix("CapEff:"))
+            .and_then(|caps| u64::from_str_radix(caps.trim(), 16).ok())
+            .is_some_and(|caps| caps & (1 << CAP_LINUX_IMMUTABLE) != 0)
+    }
+
+    fn get(file: &File) -> std::io::Result<libc::c_int> {
+        let mut flags: libc::c_int = 0;
+        // SAFETY: FS_IOC_GETFLAGS writes one int through the pointer, and
+        // `file` keeps the descriptor open
+        let ret = unsafe { libc::ioctl(file.as_raw_fd(), libc::FS_IOC_GETFLAGS, &mut flags) };
+        if ret != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(flags)
+    }
+
+    pub fn is_append_only(path: &Path) -> bool {
+        File::open(path)
+            .and_then(|file| get(&file))
+            .is_ok_and(|flags| flags & FS_APPEND_FL != 0)
+    }
+
+    pub fn set_append_only(path: &Path, on: bool) -> std::io::Result<()> {
+        let file = File::open(path)?;
+        let flags = get(&file)?;
+        let flags = if on {
+            flags | FS_APPEND_FL
+        } else {
+            flags & !FS_APPEND_FL
+        };
+        // SAFETY: FS_IOC_SETFLAGS reads one int through the pointer
+        let ret = unsafe { libc::ioctl(file.as_raw_fd(), libc::FS_IOC_SETFLAGS, &flags) };
+        if ret != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod flags {
+    use std::path::Path;
+
+    pub fn has_capability() -> bool {
+        false
+    }
+
+    pub fn is_append_only(_path: &Path) -> bool {
+        false
+    }
+
+    pub fn set_append_only(_path: &Path, _on: bool) -> std::io::Result<()> {
+        Err(std::io::ErrorKind::Unsupported.into())
+    }
+}
+
+/// Returns how `chatfile` is protected right now.
+pub fn level(chatfile: &Path) -> Protection {
+    if flags::is_append_only(chatfile) {
+        Protection::AppendOnly
+    } else {
+        Protection::Snapshot
+    }
+}
+
+/// Protects a new room: sets the append-only flag if the process may, and
+/// records a snapshot either way.
+pub fn protect(chatfile: &Path) -> Protection {
+    if flags::has_capability() {
+        match flags::set_append_only(chatfile, true) {
+            Ok(()) => log::debug("protect", "Set the append-only flag"),
+            Err(e) => log::warn(
+                "protect",
+                &format!("Could not set the append-only flag: {e}"),
+            ),
+        }
+    }
+    if let Err(e) = record(chatfile) {
+        log::warn("protect", &format!("Could not record a snapshot: {e}"));
+    }
+    level(chatfile)
+}
+
+/// Clears the append-only flag so `chatfile` can be deleted, and forgets
+/// its snapshot.
+pub fn unprotect(chatfile: &Path) -> Result<()> {
+    if flags::is_append_only(chatfile) {
+        flags::set_append_only(chatfile, false).map_err(|e| {
+            Error::PermissionDenied(format!(
+                "{} is append-only and the flag can't be cleared without CAP_LINUX_IMMUTABLE: {e}",
+                chatfile.display()
+            ))
+        })?;
+    }
+    let _ = std::fs::remove_file(snapshot_path(chatfile));
+    Ok(())
+}
+
+/// Returns the snapshot file of `chatfile`.
+pub fn snapshot_path(chatfile: &Path) -> PathBuf {
+    sidecar::path(chatfile, "snapshot")
+}
+
+/// Inode and size of a room when it was last seen, and the first
+/// replacement or truncation seen since the room was last accepted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Snapshot {
+    inode: u64,
+    size: u64,
+    violation: Option<String>,
+}
+
+impl Snapshot {
+    fn parse(content: &str) -> Option<Self> {
+        let field = |key: &str| {
+            content
+                .lines()
+                .find_map(|l| l.strip_prefix(key)?.strip_prefix('='))
+        };
+        Some(Self {
+            inode: field("inode")?.parse().ok()?,
+            size: field("size")?.parse().ok()?,
+            violation: field("tampered").map(str::to_string),
+        })
+    }
+}
+
+impl fmt::Display for Snapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "inode={}", self.inode)?;
+        writeln!(f, "size={}", self.size)?;
+        if let Some(violation) = &self.violation {
+            writeln!(f, "tampered={violation}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns the inode and size of `chatfile`.
+fn stat(chatfile: &Path) -> Result<(u64, u64)> {
+    let meta = std::fs::metadata(chatfile)?;
+    #[cfg(unix)]
+    let inode = std::os::unix::fs::MetadataExt::ino(&meta);
+    #[cfg(not(unix))]
+    let inode = 0;
+    Ok((inode, meta.len()))
+}
+
+/// Replaces the snapshot of `chatfile` with what `f` makes of the stored
+/// one (`None` if there is none) and the room's current inode and size.
+/// The snapshot stays locked throughout, and the room is only looked at
+/// once the lock is held: every append updates the snapshot after it
+/// wrote, so a size stored before we got the lock can't be larger than
+/// the one we see.
+fn update(
+    chatfile: &Path,
+    f: impl FnOnce(Option<Snapshot>, (u64, u64)) -> Snapshot,
+) -> Result<Snapshot> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(snapshot_path(chatfile))?;
+    let _lock = FileLock::exclusive(file.try_clone()?)?;
+
+    let mut content = String::new();
+    file.read_to_string(&mut content)?;
+    let snapshot = f(Snapshot::parse(&content), stat(chatfile)?);
+
+    let updated = snapshot.to_string();
+    if updated != content {
+        file.set_len(0)?;
+        file.rewind()?;
+        file.write_all(updated.as_bytes())?;
+    }
+    Ok(snapshot)
+}
+
+fn record(chatfile: &Path) -> Result<()> {
+    update(chatfile, |_, (inode, size)| Snapshot {
+        inode,
+        size,
+        violation: None,
+    })?;
+    Ok(())
+}
+
+/// Compares `chatfile` with its snapshot and takes a new one, recording
+/// the room as tampered with if it was replaced or truncated. A recorded
+/// violation stays until `accept`; it is returned, never raised, so
+/// reading and sending carry on. Failing to read or write the snapshot
+/// only logs.
+pub fn observe(chatfile: &Path) -> Option<String> {
+    let snapshot = update(chatfile, |before, (inode, size)| {
+        let violation = match before {
+            Some(Snapshot {
+                violation: Some(violation),
+                ..
+            }) => Some(violation),
+            Some(before) if before.inode != inode => Some(format!(
+                "{} was replaced by another file",
+                chatfile.display()
+            )),
+            Some(before) if size < before.size => Some(format!(
+                "{} shrank from {} to {} bytes",
+                chatfile.display(),
+                before.size,
+                size
+            )),
+            _ => None,
+        };
+        Snapshot {
+            inode,
+            size,
+            violation,
+        }
+    });
+
+    match snapshot {
+        Ok(snapshot) => snapshot.violation,
+        Err(e) => {
+            log::warn("protect", &format!("Could not check the snapshot: {e}"));
+            None
+        }
+    }
+}
+
+/// Accepts `chatfile` as it is now: forgets a recorded violation and takes
+/// a new snapshot. Returns the violation it forgot, if any.
+pub fn accept(chatfile: &Path) -> Result<Option<String>> {
+    let mut forgotten = None;
+    update(chatfile, |before, (inode, size)| {
+        forgotten = before.and_then(|b| b.violation);
+        Snapshot {
+            inode,
+            size,
+            violation: None,
+        }
+    })?;
+    Ok(forgotten)
+}
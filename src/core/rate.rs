@@ -0,0 +1,145 @@
+//! Per-agent rate limits.
+//!
+//! A room may declare `rate N/WINDOW` in its header: no agent sends more
+//! than N messages in any WINDOW. Each agent's recent sends are logged in
+//! `.<chatfile name>.rate/<name>` next to the room, one Unix time in
+//! milliseconds per line, so the limit holds across processes and
+//! machines sharing the room.
+
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::duration;
+use super::error::{Error, Result};
+use super::lock::FileLock;
+use super::presence;
+use super::sidecar;
+
+/// At most `messages` sends per agent in any `window`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimit {
+    pub messages: u32,
+    pub window: Duration,
+}
+
+/// Formats the limit as written in the header, e.g. `20/1m`.
+impl fmt::Display for RateLimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.messages, duration::format(self.window))
+    }
+}
+
+impl FromStr for RateLimit {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let (messages, window) = s
+            .split_once('/')
+            .ok_or_else(|| format!("invalid rate limit: {s} (expected e.g. 20/1m)"))?;
+        let messages = messages
+            .parse()
+            .map_err(|_| format!("invalid message count: {messages}"))?;
+        let window = duration::parse(window)?;
+        if messages == 0 || window.is_zero() {
+            return Err(format!("rate limit must allow some messages: {s}"));
+        }
+        Ok(Self { messages, window })
+    }
+}
+
+/// Returns the send log directory for `chatfile`.
+pub fn dir(chatfile: &Path) -> PathBuf {
+    sidecar::path(chatfile, "rate")
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+/// Parses a send log, keeping the sends within `window` of `now`.
+fn recent(content: &str, window: Duration, now: u64) -> Vec<u64> {
+    let since = now.saturating_sub(window.as_millis() as u64);
+    content
+        .lines()
+        .filter_map(|l| l.parse().ok())
+        .filter(|&t| t > since)
+        .collect()
+}
+
+/// Returns how many messages `name` sent within the current window.
+pub fn used(chatfile: &Path, name: &str, limit: RateLimit) -> Result<u32> {
+    let path = dir(chatfile).join(presence::file_name(name));
+    let content = match std::fs::read_to_string(path) {
+        Ok(c) => c,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e.into()),
+    };
+    Ok(recent(&content, limit.window, now_ms()).len() as u32)
+}
+
+/// Opens the send log of `name`, locked until the returned guard is
+/// dropped.
+fn lock_log(chatfile: &Path, name: &str) -> Result<(File, FileLock)> {
+    let dir = dir(chatfile);
+    std::fs::create_dir_all(&dir)?;
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(dir.join(presence::file_name(name)))?;
+    let lock = FileLock::exclusive(file.try_clone()?)?;
+    Ok((file, lock))
+}
+
+/// Logs a send by `name`, or fails with `Error::RateLimited` if it would
+/// exceed `limit`. Returns the time logged, for `forget`.
+pub fn record(chatfile: &Path, name: &str, limit: RateLimit) -> Result<u64> {
+    // Concurrent sends by the same agent must not both see room left
+    let (mut file, _lock) = lock_log(chatfile, name)?;
+
+    let mut content = String::new();
+    file.read_to_string(&mut content)?;
+    let now = now_ms();
+    let mut sends = recent(&content, limit.window, now);
+
+    if sends.len() >= limit.messages as usize {
+        let frees_at = sends[sends.len() - limit.messages as usize]
+            .saturating_add(limit.window.as_millis() as u64);
+        let retry_after = frees_at.saturating_sub(now).div_ceil(1000);
+        return Err(Error::RateLimited(limit.to_string(), retry_after));
+    }
+
+    sends.push(now);
+    write_log(&mut file, &sends)?;
+    Ok(now)
+}
+
+/// Takes back the send `record` logged at `at`, for a message that
+/// couldn't be written after all.
+pub fn forget(chatfile: &Path, name: &str, at: u64) -> Result<()> {
+    let (mut file, _lock) = lock_log(chatfile, name)?;
+
+    let mut content = String::new();
+    file.read_to_string(&mut content)?;
+    let mut sends: Vec<u64> = content.lines().filter_map(|l| l.parse().ok()).collect();
+    if let Some(i) = sends.iter().rposition(|&t| t == at) {
+        sends.remove(i);
+    }
+    write_log(&mut file, &sends)
+}
+
+fn write_log(file: &mut File, sends: &[u64]) -> Result<()> {
+    let content: String = sends.iter().map(|t| format!("{t}\n")).collect();
+    file.set_len(0)?;
+    file.rewind()?;
+    file.write_all(content.as_bytes())?;
+    Ok(())
+}
@@ -0,0 +1,102 @@
+use std::fmt;
+
+use chrono::NaiveDateTime;
+
+use super::rate::RateLimit;
+
+/// An agent in the room according to its join and leave lines.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RosterEntry {
+    pub name: String,
+    /// When it joined, in rooms created with timestamps
+    pub joined_at: Option<NaiveDateTime>,
+    /// Message id of its join line
+    pub joined_id: u64,
+}
+
+/// The moderation in effect for one name, according to the room's
+/// moderation records.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Standing {
+    /// Who kicked or banned it since it last joined
+    pub removed_by: Option<String>,
+    /// Who muted it, and until when (`None`: until unmuted)
+    pub muted: Option<(String, Option<NaiveDateTime>)>,
+    /// Who banned it
+    pub banned_by: Option<String>,
+}
+
+impl Standing {
+    /// Returns who muted it and until when, if the mute is still in effect
+    /// at `now`.
+    pub fn muted_at(&self, now: NaiveDateTime) -> Option<&(String, Option<NaiveDateTime>)> {
+        self.muted
+            .as_ref()
+            .filter(|(_, until)| until.is_none_or(|until| now < until))
+    }
+}
+
+/// Room-level settings, fixed at creation and recorded in the header line
+/// as `Options: a, b.` so every reader of the Chatfile can discover them.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RoomOptions {
+    /// Prefix chat, join and leave lines with `[YYYY-MM-DD HH:MM:SS]`
+    pub timestamps: bool,
+    /// Messages each agent may send per window, `rate N/WINDOW`
+    pub rate_limit: Option<RateLimit>,
+    /// Longest message text in characters, `max-length N`
+    pub max_length: Option<usize>,
+    /// Tag every line with a hash chaining it to the previous one
+    pub hash_chain: bool,
+}
+
+impl RoomOptions {
+    /// Reads the options from the text of a room header.
+    /// Headers without an `Options:` section yield the defaults.
+    pub fn from_header(text: &str) -> Self {
+        let mut options = Self::default();
+
+        let Some((_, list)) = text.split_once("Options: ") else {
+            return options;
+        };
+        let list = list.trim_end().trim_end_matches('.');
+
+        for option in list.split(", ") {
+            if option == "timestamps" {
+                options.timestamps = true;
+            } else if option == "hash-chain" {
+                options.hash_chain = true;
+            } else if let Some(rate) = option.strip_prefix("rate ") {
+                options.rate_limit = rate.parse().ok();
+            } else if let Some(max) = option.strip_prefix("max-length ") {
+                options.max_length = max.parse().ok();
+            }
+        }
+
+        options
+    }
+
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// Formats the options as they appear in the header, e.g. `Options: timestamps.`
+impl fmt::Display for RoomOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut list = Vec::new();
+        if self.timestamps {
+            list.push("timestamps".to_string());
+        }
+        if let Some(rate) = self.rate_limit {
+            list.push(format!("rate {rate}"));
+        }
+        if let Some(max) = self.max_length {
+            list.push(format!("max-length {max}"));
+        }
+        if self.hash_chain {
+            list.push("hash-chain".to_string());
+        }
+        write!(f, "Options: {}.", list.join(", "))
+    }
+}
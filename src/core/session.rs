@@ -1,17 +1,82 @@
+use std::collections::BTreeMap;
 use std::collections::hash_map::DefaultHasher;
 use std::hash::{Hash, Hasher};
 use std::path::{Path, PathBuf};
 
-use directories::ProjectDirs;
-
+use super::dirs;
 use super::error::{Error, Result};
+use super::pins;
 use crate::log;
 
+/// Current session file format, written as the `version=` first line.
+const FORMAT_VERSION: u32 = 1;
+
+/// A registration with a Chatfile, stored as a session file.
+///
+/// Session files are `key=value` lines starting with `version=1`. Keys this
+/// version doesn't know are kept in `extra` and written back unchanged.
+/// Files in the legacy positional format (chatfile, name, joined, cursor on
+/// lines one to four) are rewritten in the current format when loaded.
 #[derive(Debug, Clone)]
 pub struct Session {
     pub chatfile: PathBuf,
     pub name: String,
     pub joined: bool,
+    /// Byte offset in the Chatfile up to which this session has read.
+    /// `None` for sessions that have not read anything yet.
+    pub cursor: Option<u64>,
+    /// Inode of the Chatfile `cursor` points into, so a room replaced
+    /// between two reads isn't read from the old offset
+    pub inode: Option<u64>,
+    /// Fields written by other versions of `cf`, preserved on save
+    pub extra: BTreeMap<String, String>,
+    /// File this session was loaded from, which `save` writes back to
+    loaded_from: Option<PathBuf>,
+}
+
+/// A session file in the sessions directory. Its id is the file stem.
+#[derive(Debug, Clone)]
+pub struct SessionEntry {
+    pub id: String,
+    pub path: PathBuf,
+    pub session: Session,
+}
+
+/// Picks a session by name or id (`cf --session`), optionally narrowed
+/// to one Chatfile (`cf --room`). Ids may be abbreviated to a prefix.
+#[derive(Debug, Clone, Default)]
+pub struct SessionSelector {
+    pub session: Option<String>,
+    pub room: Option<PathBuf>,
+}
+
+impl SessionSelector {
+    pub fn is_empty(&self) -> bool {
+        self.session.is_none() && self.room.is_none()
+    }
+
+    fn matches(&self, entry: &SessionEntry) -> bool {
+        let session_matches = self
+            .session
+            .as_deref()
+            .is_none_or(|s| entry.session.name == s || (!s.is_empty() && entry.id.starts_with(s)));
+        let room_matches = self.room.as_deref().is_none_or(|room| {
+            let room = std::fs::canonicalize(room).unwrap_or_else(|_| room.to_path_buf());
+            entry.session.chatfile == room
+        });
+        session_matches && room_matches
+    }
+}
+
+impl std::fmt::Display for SessionSelector {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (&self.session, &self.room) {
+            (Some(s), Some(room)) => write!(f, "{s} in {}", room.display()),
+            (Some(s), None) => f.write_str(s),
+            (None, Some(room)) => write!(f, "room {}", room.display()),
+            (None, None) => f.write_str("any session"),
+        }
+    }
 }
 
 impl Session {
@@ -20,17 +85,80 @@ impl Session {
             chatfile,
             name,
             joined: false,
+            cursor: None,
+            inode: None,
+            extra: BTreeMap::new(),
+            loaded_from: None,
         }
     }
 
-    pub fn load() -> Result<Self> {
-        let path = Self::find_session_file()?;
+    /// Loads the session `selector` picks; see `find_session_file`.
+    pub fn load(selector: &SessionSelector) -> Result<Self> {
+        let path = Self::find_session_file(selector)?;
         log::debug("Session", &format!("Loading from: {}", path.display()));
         Self::load_from(&path)
     }
 
+    /// Loads a session file, migrating it in place if it uses the legacy
+    /// format.
     pub fn load_from(path: &Path) -> Result<Self> {
         let content = std::fs::read_to_string(path)?;
+
+        let mut session = if content.starts_with("version=") {
+            Self::parse(&content)?
+        } else {
+            let session = Self::parse_legacy(&content)?;
+            log::info(
+                "Session",
+                &format!("Migrating legacy session file: {}", path.display()),
+            );
+            session.save_to(path)?;
+            session
+        };
+        session.loaded_from = Some(path.to_path_buf());
+        Ok(session)
+    }
+
+    fn parse(content: &str) -> Result<Self> {
+        let mut fields: BTreeMap<String, String> = content
+            .lines()
+            .filter(|line| !line.trim().is_empty() && !line.starts_with('#'))
+            .filter_map(|line| line.split_once('='))
+            .map(|(key, value)| (key.trim().to_string(), value.trim().to_string()))
+            .collect();
+
+        let version: u32 = fields
+            .remove("version")
+            .and_then(|v| v.parse().ok())
+            .ok_or(Error::InvalidSession)?;
+        if version > FORMAT_VERSION {
+            log::warn(
+                "Session",
+                &format!("Session file version {version} is newer than this cf supports"),
+            );
+            return Err(Error::InvalidSession);
+        }
+
+        let chatfile = fields.remove("chatfile").ok_or(Error::InvalidSession)?;
+        let name = fields.remove("name").ok_or(Error::InvalidSession)?;
+        let joined = fields.remove("joined").is_some_and(|v| v == "true");
+        let cursor = fields.remove("cursor").and_then(|v| v.parse().ok());
+        let inode = fields.remove("inode").and_then(|v| v.parse().ok());
+
+        Ok(Self {
+            chatfile: chatfile.into(),
+            name,
+            joined,
+            cursor,
+            inode,
+            extra: fields,
+            loaded_from: None,
+        })
+    }
+
+    /// Parses the original positional format: chatfile, name, `yes` if
+    /// joined, and the read cursor, one per line.
+    fn parse_legacy(content: &str) -> Result<Self> {
         let mut lines = content.lines();
 
         let chatfile = lines.next().ok_or(Error::InvalidSession)?.trim().into();
@@ -43,15 +171,26 @@ impl Session {
 
         let joined = lines.next().is_some_and(|s| s.trim() == "yes");
 
+        let cursor = lines.next().and_then(|s| s.trim().parse().ok());
+
         Ok(Self {
             chatfile,
             name,
             joined,
+            cursor,
+            inode: None,
+            extra: BTreeMap::new(),
+            loaded_from: None,
         })
     }
 
+    /// Writes the session back to the file it was loaded from, or for a
+    /// new session to `CF_SESSION` or the sessions directory.
     pub fn save(&self) -> Result<()> {
-        let path = Self::session_path_for(&self.chatfile, &self.name)?;
+        let path = match &self.loaded_from {
+            Some(path) => path.clone(),
+            None => Self::session_path_for(&self.chatfile, &self.name)?,
+        };
         log::debug("Session", &format!("Saving to: {}", path.display()));
         self.save_to(&path)
     }
@@ -62,29 +201,25 @@ impl Session {
             std::fs::create_dir_all(parent)?;
         }
 
-        let joined_str = if self.joined { "yes" } else { "" };
-        let content = format!(
-            "{}\n{}\n{}\n",
+        let mut content = format!(
+            "version={FORMAT_VERSION}\nchatfile={}\nname={}\njoined={}\n",
             self.chatfile.display(),
             self.name,
-            joined_str
+            self.joined
         );
+        if let Some(cursor) = self.cursor {
+            content.push_str(&format!("cursor={cursor}\n"));
+        }
+        if let Some(inode) = self.inode {
+            content.push_str(&format!("inode={inode}\n"));
+        }
+        for (key, value) in &self.extra {
+            content.push_str(&format!("{key}={value}\n"));
+        }
         std::fs::write(path, content)?;
         Ok(())
     }
 
-    /// Returns the XDG data directory for chatfiles sessions.
-    /// ~/.local/share/chatfiles/sessions/
-    fn sessions_dir() -> Result<PathBuf> {
-        let proj_dirs = ProjectDirs::from("", "", "chatfiles").ok_or_else(|| {
-            Error::Io(std::io::Error::new(
-                std::io::ErrorKind::NotFound,
-                "Could not determine XDG directories",
-            ))
-        })?;
-        Ok(proj_dirs.data_dir().join("sessions"))
-    }
-
     /// Generates a session filename based on chatfile path + name hash.
     /// Each unique (chatfile, name) pair gets its own session file.
     fn session_filename(chatfile: &Path, name: &str) -> String {
@@ -98,6 +233,18 @@ impl Session {
         format!("{:016x}.session", hash)
     }
 
+    /// Checks whether a session for this chatfile and name exists in the
+    /// sessions directory.
+    pub fn is_registered(chatfile: &Path, name: &str) -> Result<bool> {
+        let path = dirs::sessions_dir()?.join(Self::session_filename(chatfile, name));
+        Ok(path.exists())
+    }
+
+    /// Returns the path `save` writes this session to.
+    pub fn path(&self) -> Result<PathBuf> {
+        Self::session_path_for(&self.chatfile, &self.name)
+    }
+
     /// Returns the session file path for a given chatfile and name.
     fn session_path_for(chatfile: &Path, name: &str) -> Result<PathBuf> {
         // CF_SESSION env var takes precedence
@@ -105,12 +252,22 @@ impl Session {
             return Ok(PathBuf::from(env_path));
         }
 
-        let sessions_dir = Self::sessions_dir()?;
+        let sessions_dir = dirs::sessions_dir()?;
         let filename = Self::session_filename(chatfile, name);
         Ok(sessions_dir.join(filename))
     }
 
-    fn find_session_file() -> Result<PathBuf> {
+    /// Returns the path of the session file commands operate on.
+    ///
+    /// In order: `selector` unless it is empty (`cf --session/--room`),
+    /// `CF_SESSION`, the session pinned to the working directory or its
+    /// nearest ancestor (`cf use`), then the most recently modified
+    /// session file.
+    pub fn find_session_file(selector: &SessionSelector) -> Result<PathBuf> {
+        if !selector.is_empty() {
+            return Ok(Self::resolve(selector)?.path);
+        }
+
         // CF_SESSION env var takes precedence
         if let Ok(env_path) = std::env::var("CF_SESSION") {
             let path = PathBuf::from(&env_path);
@@ -120,26 +277,38 @@ impl Session {
             return Err(Error::NoSession);
         }
 
+        let cwd = std::env::current_dir()?;
+        if let Some(id) = pins::pinned(&cwd)? {
+            log::debug("Session", &format!("Using session {id} pinned by cf use"));
+            let pinned = SessionSelector {
+                session: Some(id),
+                room: None,
+            };
+            return Ok(Self::resolve(&pinned)?.path);
+        }
+
         // Check XDG sessions directory for any session file
-        if let Ok(sessions_dir) = Self::sessions_dir() {
-            if sessions_dir.exists() {
-                // Find the most recently modified session file
-                if let Ok(entries) = std::fs::read_dir(&sessions_dir) {
-                    let mut sessions: Vec<_> = entries
-                        .filter_map(|e| e.ok())
-                        .filter(|e| e.path().extension().is_some_and(|ext| ext == "session"))
-                        .collect();
-
-                    sessions.sort_by_key(|e| {
-                        e.metadata()
-                            .and_then(|m| m.modified())
-                            .unwrap_or(std::time::SystemTime::UNIX_EPOCH)
-                    });
-
-                    if let Some(latest) = sessions.last() {
-                        return Ok(latest.path());
-                    }
+        if let Ok(mut sessions) = Self::list_sessions() {
+            // Find the most recently modified session file
+            sessions.sort_by_key(|path| {
+                std::fs::metadata(path)
+                    .and_then(|m| m.modified())
+                    .unwrap_or(std::time::SystemTime::UNIX_EPOCH)
+            });
+
+            if let Some(latest) = sessions.last() {
+                if sessions.len() > 1 {
+                    log::warn(
+                        "Session",
+                        &format!(
+                            "{} sessions registered, using the newest ({}). \
+                             Pick one with cf --session or cf use",
+                            sessions.len(),
+                            latest.display()
+                        ),
+                    );
                 }
+                return Ok(latest.clone());
             }
         }
 
@@ -147,7 +316,7 @@ impl Session {
         let local = PathBuf::from(".cf_session");
         if local.exists() {
             log::debug("Session", "Found legacy .cf_session in CWD");
-            return Ok(local);
+            return Self::migrate_legacy_file(&local);
         }
 
         // Legacy: check home directory
@@ -155,16 +324,31 @@ impl Session {
             let home_session = base_dirs.home_dir().join(".cf_session");
             if home_session.exists() {
                 log::debug("Session", "Found legacy .cf_session in home");
-                return Ok(home_session);
+                return Self::migrate_legacy_file(&home_session);
             }
         }
 
         Err(Error::NoSession)
     }
 
+    /// Moves a legacy `.cf_session` file into the sessions directory and
+    /// returns its new path.
+    fn migrate_legacy_file(legacy: &Path) -> Result<PathBuf> {
+        let session = Self::load_from(legacy)?;
+        let path =
+            dirs::sessions_dir()?.join(Self::session_filename(&session.chatfile, &session.name));
+        session.save_to(&path)?;
+        std::fs::remove_file(legacy)?;
+        log::info(
+            "Session",
+            &format!("Moved {} to {}", legacy.display(), path.display()),
+        );
+        Ok(path)
+    }
+
     /// Lists all active sessions in the XDG sessions directory.
     pub fn list_sessions() -> Result<Vec<PathBuf>> {
-        let sessions_dir = Self::sessions_dir()?;
+        let sessions_dir = dirs::sessions_dir()?;
         if !sessions_dir.exists() {
             return Ok(Vec::new());
         }
@@ -178,9 +362,64 @@ impl Session {
         Ok(sessions)
     }
 
+    /// Loads every readable session in the sessions directory, sorted by
+    /// room and name.
+    pub fn entries() -> Result<Vec<SessionEntry>> {
+        let mut entries: Vec<_> = Self::list_sessions()?
+            .into_iter()
+            .filter_map(|path| {
+                let id = path.file_stem()?.to_string_lossy().into_owned();
+                let session = Self::load_from(&path).ok()?;
+                Some(SessionEntry { id, path, session })
+            })
+            .collect();
+        entries.sort_by(|a, b| {
+            (&a.session.chatfile, &a.session.name).cmp(&(&b.session.chatfile, &b.session.name))
+        });
+        Ok(entries)
+    }
+
+    /// Finds the one session matching `selector`.
+    pub fn resolve(selector: &SessionSelector) -> Result<SessionEntry> {
+        let mut matching: Vec<_> = Self::entries()?
+            .into_iter()
+            .filter(|e| selector.matches(e))
+            .collect();
+
+        if let Some(s) = &selector.session {
+            // An exact id beats sessions whose name happens to match
+            if let Some(i) = matching.iter().position(|e| &e.id == s) {
+                return Ok(matching.swap_remove(i));
+            }
+            // And an exact name beats ids that merely start with it
+            if matching.iter().any(|e| e.session.name == *s) {
+                matching.retain(|e| e.session.name == *s);
+            }
+        }
+
+        match matching.len() {
+            0 => Err(Error::UnknownSession(selector.to_string())),
+            1 => Ok(matching.remove(0)),
+            _ => Err(Error::AmbiguousSession(selector.to_string())),
+        }
+    }
+
+    /// Loads the session registered to `chatfile` under `name` from the
+    /// sessions directory, together with the path it was loaded from.
+    pub fn find_registered(chatfile: &Path, name: &str) -> Result<Option<(Self, PathBuf)>> {
+        let path = dirs::sessions_dir()?.join(Self::session_filename(chatfile, name));
+        if !path.exists() {
+            return Ok(None);
+        }
+        Ok(Some((Self::load_from(&path)?, path)))
+    }
+
     /// Deletes the session file for the current chatfile.
     pub fn delete(&self) -> Result<()> {
-        let path = Self::session_path_for(&self.chatfile, &self.name)?;
+        let path = match &self.loaded_from {
+            Some(path) => path.clone(),
+            None => Self::session_path_for(&self.chatfile, &self.name)?,
+        };
         if path.exists() {
             std::fs::remove_file(&path)?;
             log::debug("Session", &format!("Deleted: {}", path.display()));
@@ -0,0 +1,32 @@
+//! Files kept next to a Chatfile, named `.<chatfile name>.<suffix>`:
+//! heartbeats, roles, send logs, moderation standings and snapshots.
+//! Keeping them beside the room rather than in the data directory means
+//! every agent sharing the room's directory, on any machine, sees the same
+//! ones.
+
+use std::path::{Path, PathBuf};
+
+use super::error::Result;
+
+/// Returns the sidecar of `chatfile` with `suffix`, e.g.
+/// `.room.Chatfile.acl` for `room.Chatfile` and `acl`.
+pub fn path(chatfile: &Path, suffix: &str) -> PathBuf {
+    chatfile.with_file_name(format!(".{}.{suffix}", file_name(chatfile)))
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+/// Replaces the file at `path` with `content` by writing a temporary file
+/// next to it and renaming that over `path`, so readers never see a
+/// half-written file. The temporary name starts with a dot, which lets
+/// directory listings skip it.
+pub fn write_atomic(path: &Path, content: &str) -> Result<()> {
+    let tmp = path.with_file_name(format!(".{}.{}", file_name(path), std::process::id()));
+    std::fs::write(&tmp, content)?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
@@ -0,0 +1,57 @@
+//! Message ids and reply threads.
+//!
+//! A message's id is its 1-based line number in the Chatfile, so the room
+//! header is `#1`. A reply starts its text with `>>id `.
+
+use std::collections::HashSet;
+
+use super::chatfile::Chatfile;
+use super::error::{Error, Result};
+use super::message::Message;
+
+const REPLY_MARKER: &str = ">>";
+
+/// Prefixes `message` with a reference to `reply_to`.
+pub fn reply_text(reply_to: u64, message: &str) -> String {
+    format!("{REPLY_MARKER}{reply_to} {message}")
+}
+
+/// Returns the id a message replies to, if it is a reply.
+pub fn reply_to(message: &Message) -> Option<u64> {
+    let Message::Chat { text, .. } = message else {
+        return None;
+    };
+    let rest = text.strip_prefix(REPLY_MARKER)?;
+    let (id, _) = rest.split_once(' ')?;
+    id.parse().ok()
+}
+
+/// Reconstructs the thread containing message `id`: its root and every
+/// reply below it, in file order, paired with their ids.
+pub fn thread(chatfile: &Chatfile, id: u64) -> Result<Vec<(u64, String)>> {
+    let lines = chatfile.read_last_numbered(usize::MAX)?;
+    if id == 0 || id > lines.len() as u64 {
+        return Err(Error::UnknownMessage(id));
+    }
+
+    let parent = |id: u64| reply_to(&Message::parse(&lines[id as usize - 1].1));
+
+    // Walk up to the root; replies only point backwards, so this ends
+    let mut root = id;
+    while let Some(p) = parent(root).filter(|&p| p >= 1 && p < root) {
+        root = p;
+    }
+
+    let mut members = HashSet::from([root]);
+    let mut thread = Vec::new();
+    for (line_id, line) in lines.into_iter().skip(root as usize - 1) {
+        let in_thread = line_id == root
+            || reply_to(&Message::parse(&line)).is_some_and(|p| members.contains(&p));
+        if in_thread {
+            members.insert(line_id);
+            thread.push((line_id, line));
+        }
+    }
+
+    Ok(thread)
+}
@@ -0,0 +1,92 @@
+use std::path::Path;
+use std::time::Duration;
+
+use super::duration;
+use crate::log;
+
+/// Poll interval used when `CF_POLL_INTERVAL` is not set.
+pub const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How `Chatfile::watch_many` notices appends.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchMode {
+    /// Kernel change notifications (inotify, kqueue, ...)
+    Notify,
+    /// Re-check the file size at a fixed interval. Needed on network
+    /// filesystems, where writes from other hosts raise no notifications.
+    Poll(Duration),
+}
+
+impl WatchMode {
+    /// Picks the mode for watching `paths`: polling if any of them is on a
+    /// network filesystem, notifications otherwise.
+    ///
+    /// `CF_WATCH=notify|poll|auto` overrides the detection and
+    /// `CF_POLL_INTERVAL` (e.g. `500ms`, `2s`) sets the poll interval.
+    pub fn for_paths<'a>(paths: impl IntoIterator<Item = &'a Path>) -> Self {
+        let interval = match std::env::var("CF_POLL_INTERVAL") {
+            Ok(v) => duration::parse(&v).unwrap_or_else(|e| {
+                log::warn("watch", &format!("Ignoring CF_POLL_INTERVAL: {e}"));
+                DEFAULT_POLL_INTERVAL
+            }),
+            Err(_) => DEFAULT_POLL_INTERVAL,
+        };
+
+        match std::env::var("CF_WATCH").as_deref() {
+            Ok("notify") => return Self::Notify,
+            Ok("poll") => return Self::Poll(interval),
+            Ok("auto") | Err(_) => {}
+            Ok(other) => log::warn(
+                "watch",
+                &format!("Ignoring CF_WATCH={other} (use notify, poll or auto)"),
+            ),
+        }
+
+        for path in paths {
+            if is_network_fs(path) {
+                log::debug(
+                    "watch",
+                    &format!("{} is on a network filesystem, polling", path.display()),
+                );
+                return Self::Poll(interval);
+            }
+        }
+        Self::Notify
+    }
+}
+
+/// Checks whether `path` lives on a filesystem where change notifications
+/// don't see writes made by other hosts (NFS, SMB, FUSE mounts like sshfs, ...).
+#[cfg(target_os = "linux")]
+pub fn is_network_fs(path: &Path) -> bool {
+    use std::os::unix::ffi::OsStrExt;
+
+    const NETWORK_FS_MAGIC: &[u32] = &[
+        0x6969,      // NFS
+        0x517B,      // SMB
+        0xFF53_4D42, // CIFS
+        0xFE53_4D42, // SMB2
+        0x6573_5546, // FUSE (sshfs, davfs2, rclone, ...)
+        0x7375_7245, // Coda (davfs2 without FUSE)
+        0x0102_1997, // 9P
+        0x5346_414F, // AFS
+        0x00C3_6400, // Ceph
+    ];
+
+    let Ok(c_path) = std::ffi::CString::new(path.as_os_str().as_bytes()) else {
+        return false;
+    };
+    // SAFETY: statfs only writes into `buf`, and `c_path` is NUL-terminated
+    let mut buf: libc::statfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statfs(c_path.as_ptr(), &mut buf) } != 0 {
+        return false;
+    }
+
+    // f_type's width differs between architectures; magic numbers are 32-bit
+    NETWORK_FS_MAGIC.contains(&(buf.f_type as u32))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn is_network_fs(_path: &Path) -> bool {
+    false
+}
@@ -10,5 +10,9 @@ pub mod log;
 #[cfg(feature = "web")]
 pub mod web;
 
-pub use core::ops::{await_message, join, leave, read, register, send, status};
-pub use core::{Chatfile, Error, Result, Session};
+pub use core::ops::{
+    AwaitFilter, RoomMessages, await_filtered, await_message, await_message_timeout, await_parsed,
+    await_rooms, join, leave, poll_filtered, poll_messages, read, read_new, read_parsed, register,
+    rename, send, status, unregister,
+};
+pub use core::{Chatfile, Error, Message, Result, Session};
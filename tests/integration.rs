@@ -1,11 +1,55 @@
 use std::fs;
+use std::path::Path;
+use std::process::Command;
+use std::time::{Duration, Instant};
 
-use chatfiles::core::{Chatfile, names};
+use chatfiles::core::rate::{self, RateLimit};
+use chatfiles::core::{
+    Chatfile, Error, Message, RoomOptions, acl, chain, clear, duration, lines, moderation, names,
+    presence, protect, thread,
+};
 use chatfiles::log;
 
+/// Removes a room a test created, with its sidecar files. Rooms are
+/// append-only when the tests run with CAP_LINUX_IMMUTABLE; this clears
+/// the flag first.
+fn remove_room(path: impl AsRef<Path>) -> chatfiles::core::Result<()> {
+    clear::remove_room(path.as_ref())
+}
+
+/// A `cf` invocation that keeps its sessions and data in `home`, as the
+/// session named `session` or else the newest one. Tests that need more
+/// control over the process adjust the returned command.
+fn cf_command(home: &Path, session: Option<&str>, args: &[&str]) -> Command {
+    let mut cmd = Command::new(env!("CARGO_BIN_EXE_cf"));
+    if let Some(session) = session {
+        cmd.args(["--session", session]);
+    }
+    cmd.args(args).env("CF_HOME", home).env_remove("CF_SESSION");
+    cmd
+}
+
+/// Runs `cmd` to completion, returning its exit code and stdout.
+fn run(cmd: &mut Command) -> (Option<i32>, String) {
+    let out = cmd.output().unwrap();
+    (out.status.code(), String::from_utf8(out.stdout).unwrap())
+}
+
+/// Runs `cf` with `args`; see `cf_command`.
+fn cf(home: &Path, session: Option<&str>, args: &[&str]) -> (Option<i32>, String) {
+    run(&mut cf_command(home, session, args))
+}
+
+/// Returns the stdout of a `cf` run, which must succeed.
+fn cf_ok(home: &Path, args: &[&str]) -> String {
+    let (code, out) = cf(home, None, args);
+    assert_eq!(code, Some(0), "cf {args:?} failed");
+    out
+}
+
 #[test]
 fn test_chatfile_create_and_read() {
-    let _ = fs::remove_file("test_create.Chatfile");
+    let _ = remove_room("test_create.Chatfile");
 
     let cf = Chatfile::create(Some("test_create")).unwrap();
     assert!(cf.path.exists());
@@ -16,12 +60,12 @@ fn test_chatfile_create_and_read() {
     assert!(lines.len() >= 2);
     assert!(lines.last().unwrap().contains("Hello world"));
 
-    fs::remove_file("test_create.Chatfile").unwrap();
+    remove_room("test_create.Chatfile").unwrap();
 }
 
 #[test]
 fn test_name_generation() {
-    let _ = fs::remove_file("test_namegen.Chatfile");
+    let _ = remove_room("test_namegen.Chatfile");
 
     let cf = Chatfile::create(Some("test_namegen")).unwrap();
     let name = names::generate(&cf).unwrap();
@@ -30,12 +74,12 @@ fn test_name_generation() {
     let parts: Vec<&str> = name.split('-').collect();
     assert_eq!(parts.len(), 3);
 
-    fs::remove_file("test_namegen.Chatfile").unwrap();
+    remove_room("test_namegen.Chatfile").unwrap();
 }
 
 #[test]
 fn test_list_rooms() {
-    let _ = fs::remove_file("test_list.Chatfile");
+    let _ = remove_room("test_list.Chatfile");
 
     let _ = Chatfile::create(Some("test_list")).unwrap();
     let rooms = Chatfile::list_rooms().unwrap();
@@ -46,12 +90,12 @@ fn test_list_rooms() {
             .any(|p| p.to_string_lossy().contains("test_list"))
     );
 
-    fs::remove_file("test_list.Chatfile").unwrap();
+    remove_room("test_list.Chatfile").unwrap();
 }
 
 #[test]
 fn test_message_format() {
-    let _ = fs::remove_file("test_msg.Chatfile");
+    let _ = remove_room("test_msg.Chatfile");
 
     let cf = Chatfile::create(Some("test_msg")).unwrap();
     cf.send("agent-1", "Test message").unwrap();
@@ -62,7 +106,7 @@ fn test_message_format() {
     assert!(lines.iter().any(|l| l == "agent-1: Test message"));
     assert!(lines.iter().any(|l| l == "[agent-2 joined]"));
 
-    fs::remove_file("test_msg.Chatfile").unwrap();
+    remove_room("test_msg.Chatfile").unwrap();
 }
 
 #[test]
@@ -73,3 +117,1580 @@ fn test_logging() {
     log::warn("TEST", "Warning message");
     log::error("TEST", "Error message");
 }
+
+#[test]
+fn test_message_round_trip() {
+    let _ = remove_room("test_parse.Chatfile");
+
+    let cf = Chatfile::create(Some("test_parse")).unwrap();
+    cf.send("agent-1", "Hello: world").unwrap();
+    cf.announce_join("agent-2").unwrap();
+    cf.announce_leave("agent-2").unwrap();
+    cf.append("[admin]: Be nice").unwrap();
+
+    let lines = cf.read_last(10).unwrap();
+    for line in &lines {
+        assert_eq!(Message::parse(line).to_string(), *line);
+    }
+
+    let messages = cf.read_messages(10).unwrap();
+    assert!(matches!(messages[0], Message::System { .. }));
+    assert_eq!(
+        messages[1],
+        Message::Chat {
+            name: "agent-1".into(),
+            text: "Hello: world".into(),
+            timestamp: None
+        }
+    );
+    assert_eq!(
+        messages[2],
+        Message::Join {
+            name: "agent-2".into(),
+            timestamp: None
+        }
+    );
+    assert_eq!(
+        messages[3],
+        Message::Leave {
+            name: "agent-2".into(),
+            timestamp: None
+        }
+    );
+    assert_eq!(
+        messages[4],
+        Message::Admin {
+            prefix: "admin".into(),
+            text: "Be nice".into()
+        }
+    );
+
+    remove_room("test_parse.Chatfile").unwrap();
+}
+
+#[test]
+fn test_message_parse_unknown() {
+    for line in [
+        "",
+        "no colon here",
+        ":leading",
+        "[unclosed",
+        "[a]b]: x",
+        "name:nospace",
+    ] {
+        assert_eq!(Message::parse(line), Message::Other(line.to_string()));
+        assert_eq!(Message::parse(line).to_string(), line);
+    }
+    assert_eq!(Message::parse("bob: hi").sender(), Some("bob"));
+    assert_eq!(Message::parse("[bob joined]").sender(), None);
+}
+
+#[test]
+fn test_read_from_offset() {
+    let _ = remove_room("test_offset.Chatfile");
+
+    let cf = Chatfile::create(Some("test_offset")).unwrap();
+    let start = cf.size().unwrap();
+
+    cf.send("agent-1", "first").unwrap();
+    cf.send("agent-2", "second").unwrap();
+
+    let (lines, next) = cf.read_from(start).unwrap();
+    assert_eq!(lines, vec!["agent-1: first", "agent-2: second"]);
+    assert_eq!(next, cf.size().unwrap());
+
+    // A partially written line is left for the next read
+    {
+        use std::io::Write;
+        let mut file = fs::OpenOptions::new().append(true).open(&cf.path).unwrap();
+        write!(file, "agent-3: par").unwrap();
+    }
+    let (lines, partial) = cf.read_from(next).unwrap();
+    assert!(lines.is_empty());
+    assert_eq!(partial, next);
+
+    cf.append("tial").unwrap();
+    let (lines, _) = cf.read_from(partial).unwrap();
+    assert_eq!(lines, vec!["agent-3: partial"]);
+
+    let (lines, end) = cf.watch_from(start, None).unwrap();
+    assert_eq!(lines.len(), 3);
+
+    let deadline = Instant::now() + Duration::from_millis(50);
+    assert!(matches!(
+        cf.watch_from(end, Some(deadline)),
+        Err(Error::Timeout)
+    ));
+
+    remove_room("test_offset.Chatfile").unwrap();
+}
+
+#[test]
+fn test_read_new_cursor() {
+    let _ = remove_room("test_cursor.Chatfile");
+    let home = std::env::temp_dir().join("chatfiles_test_cursor");
+    let _ = fs::remove_dir_all(&home);
+
+    let cf = Chatfile::create(Some("test_cursor")).unwrap();
+    cf.send("agent-1", "before register").unwrap();
+
+    cf_ok(&home, &["register", "test_cursor.Chatfile", "-n", "reader"]);
+    cf.send("agent-1", "one").unwrap();
+    cf.send("agent-2", "two").unwrap();
+
+    assert_eq!(
+        cf_ok(&home, &["read", "--new"]),
+        "agent-1: one\nagent-2: two\n"
+    );
+    assert_eq!(cf_ok(&home, &["read", "--new"]), "");
+
+    cf_ok(&home, &["join"]);
+    cf.send("agent-1", "three").unwrap();
+    cf.send("agent-2", "four").unwrap();
+    assert_eq!(cf_ok(&home, &["await"]), "agent-1: three\nagent-2: four\n");
+
+    assert_eq!(cf_ok(&home, &["await", "--poll"]), "");
+    cf.send("agent-1", "five").unwrap();
+    assert_eq!(cf_ok(&home, &["await", "--poll"]), "agent-1: five\n");
+
+    let timed_out = cf_command(&home, None, &["await", "--timeout", "100ms"])
+        .status()
+        .unwrap();
+    assert_eq!(timed_out.code(), Some(124));
+
+    remove_room("test_cursor.Chatfile").unwrap();
+    fs::remove_dir_all(&home).unwrap();
+}
+
+#[test]
+fn test_read_last_tail() {
+    let _ = remove_room("test_tail.Chatfile");
+
+    let cf = Chatfile::create(Some("test_tail")).unwrap();
+    // Enough lines to span several backwards-read chunks
+    for i in 0..2000 {
+        cf.send("agent-1", &format!("message {i}")).unwrap();
+    }
+
+    let lines = cf.read_last(3).unwrap();
+    assert_eq!(
+        lines,
+        vec![
+            "agent-1: message 1997",
+            "agent-1: message 1998",
+            "agent-1: message 1999"
+        ]
+    );
+    assert_eq!(cf.read_last(5000).unwrap().len(), 2001);
+    assert!(cf.read_last(0).unwrap().is_empty());
+    assert_eq!(
+        cf.last_line().unwrap().as_deref(),
+        Some("agent-1: message 1999")
+    );
+    assert!(cf.name_exists("agent-1").unwrap());
+    assert!(!cf.name_exists("agent-2").unwrap());
+
+    // A trailing line without newline is still the last line
+    {
+        use std::io::Write;
+        let mut file = fs::OpenOptions::new().append(true).open(&cf.path).unwrap();
+        write!(file, "agent-2: partial").unwrap();
+    }
+    assert_eq!(
+        cf.read_last(2).unwrap(),
+        vec!["agent-1: message 1999", "agent-2: partial"]
+    );
+
+    remove_room("test_tail.Chatfile").unwrap();
+}
+
+#[test]
+fn test_concurrent_appends() {
+    let _ = remove_room("test_stress.Chatfile");
+
+    let cf = Chatfile::create(Some("test_stress")).unwrap();
+    const WRITERS: usize = 16;
+    const MESSAGES: usize = 25;
+
+    let handles: Vec<_> = (0..WRITERS)
+        .map(|w| {
+            std::thread::spawn(move || {
+                let cf = Chatfile::open("test_stress.Chatfile").unwrap();
+                let name = format!("writer-{w}");
+                // Mix small lines with lines well above a page
+                for i in 0..MESSAGES {
+                    let len = if i % 2 == 0 { 16 } else { 3 * 4096 };
+                    let body: String = std::iter::repeat_n((b'a' + w as u8) as char, len).collect();
+                    cf.send(&name, &format!("{i} {body}")).unwrap();
+                }
+            })
+        })
+        .collect();
+    for h in handles {
+        h.join().unwrap();
+    }
+
+    let lines = cf.read_last(usize::MAX).unwrap();
+    assert_eq!(lines.len(), 1 + WRITERS * MESSAGES);
+
+    let mut seen = std::collections::HashSet::new();
+    for line in &lines[1..] {
+        let Message::Chat { name, text, .. } = Message::parse(line) else {
+            panic!("malformed line: {line:.80}");
+        };
+        let w: usize = name.strip_prefix("writer-").unwrap().parse().unwrap();
+        let (i, body) = text.split_once(' ').unwrap();
+        let expected = (b'a' + w as u8) as char;
+        assert!(body.chars().all(|c| c == expected), "interleaved line");
+        assert!(seen.insert((w, i.to_string())), "duplicate line");
+    }
+    // Racing appends must not look like a truncation to each other
+    assert_eq!(protect::observe(&cf.path), None);
+
+    remove_room("test_stress.Chatfile").unwrap();
+}
+
+#[test]
+fn test_concurrent_register_unique_names() {
+    let _ = remove_room("test_regrace.Chatfile");
+    let home = std::env::temp_dir().join("chatfiles_test_regrace");
+    let _ = fs::remove_dir_all(&home);
+
+    let _ = Chatfile::create(Some("test_regrace")).unwrap();
+
+    let children: Vec<_> = (0..12)
+        .map(|_| {
+            cf_command(
+                &home,
+                None,
+                &["register", "test_regrace.Chatfile", "-n", "bob"],
+            )
+            .stdout(std::process::Stdio::piped())
+            .stderr(std::process::Stdio::null())
+            .spawn()
+            .unwrap()
+        })
+        .collect();
+
+    let mut names = std::collections::HashSet::new();
+    for child in children {
+        let out = child.wait_with_output().unwrap();
+        assert!(out.status.success());
+        let name = String::from_utf8(out.stdout).unwrap().trim().to_string();
+        assert!(names.insert(name.clone()), "duplicate name: {name}");
+    }
+    assert!(names.contains("bob"));
+    assert_eq!(names.len(), 12);
+
+    remove_room("test_regrace.Chatfile").unwrap();
+    fs::remove_dir_all(&home).unwrap();
+}
+
+#[test]
+fn test_multiline_escape() {
+    let _ = remove_room("test_multiline.Chatfile");
+
+    let cf = Chatfile::create(Some("test_multiline")).unwrap();
+    let bodies = [
+        "line one\nline two",
+        "crlf\r\nend",
+        "C:\\dir\\new",
+        "literal \\n stays",
+        "trailing \\",
+        "double \\\\",
+        "\\\n",
+    ];
+    for body in bodies {
+        cf.send("agent-1", body).unwrap();
+    }
+
+    let lines = cf.read_last(bodies.len()).unwrap();
+    assert_eq!(lines.len(), bodies.len());
+    assert_eq!(lines[0], "agent-1: line one\\nline two");
+    assert_eq!(lines[2], "agent-1: C:\\dir\\\\new");
+    for (line, body) in lines.iter().zip(bodies) {
+        let msg = Message::parse(line);
+        assert_eq!(msg.text(), Some(body));
+        assert_eq!(msg.to_string(), *line);
+    }
+
+    // Legacy lines with plain backslashes keep their meaning
+    assert_eq!(
+        Message::parse("agent-1: match \\d+ in C:\\tmp").text(),
+        Some("match \\d+ in C:\\tmp")
+    );
+    // but a literal backslash-n in them now reads as a line break
+    assert_eq!(
+        Message::parse("agent-1: use \\n here").text(),
+        Some("use \n here")
+    );
+
+    remove_room("test_multiline.Chatfile").unwrap();
+}
+
+#[test]
+fn test_send_from_stdin() {
+    use std::io::Write;
+
+    let _ = remove_room("test_stdin.Chatfile");
+    let home = std::env::temp_dir().join("chatfiles_test_stdin");
+    let _ = fs::remove_dir_all(&home);
+
+    let cf = Chatfile::create(Some("test_stdin")).unwrap();
+    cf_ok(&home, &["register", "test_stdin.Chatfile", "-n", "piper"]);
+    cf_ok(&home, &["join"]);
+
+    let mut child = cf_command(&home, None, &["send", "-"])
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::null())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(b"fn main() {\n    panic!()\n}\n")
+        .unwrap();
+    assert!(child.wait().unwrap().success());
+
+    assert_eq!(
+        cf.last_line().unwrap().as_deref(),
+        Some("piper: fn main() {\\n    panic!()\\n}")
+    );
+    assert_eq!(
+        cf_ok(&home, &["read", "1"]),
+        "piper: fn main() {\n    panic!()\n}\n"
+    );
+
+    remove_room("test_stdin.Chatfile").unwrap();
+    fs::remove_dir_all(&home).unwrap();
+}
+
+#[test]
+fn test_timestamped_room() {
+    let _ = remove_room("test_stamped.Chatfile");
+
+    let options = RoomOptions {
+        timestamps: true,
+        ..Default::default()
+    };
+    let cf = Chatfile::create_with(Some("test_stamped"), options.clone()).unwrap();
+    cf.announce_join("agent-1").unwrap();
+    cf.send("agent-1", "stamped").unwrap();
+
+    // Options survive reopening, since they live in the header
+    let cf = Chatfile::open("test_stamped.Chatfile").unwrap();
+    assert_eq!(cf.options, options);
+
+    let lines = cf.read_last(3).unwrap();
+    assert!(lines[0].ends_with("Options: timestamps."));
+    for line in &lines {
+        assert_eq!(Message::parse(line).to_string(), *line);
+    }
+
+    let join = Message::parse(&lines[1]);
+    assert!(matches!(join, Message::Join { .. }));
+    assert!(join.timestamp().is_some());
+
+    let chat = Message::parse(&lines[2]);
+    assert!(lines[2].starts_with('['));
+    assert_eq!(chat.sender(), Some("agent-1"));
+    assert_eq!(chat.text(), Some("stamped"));
+    assert!(chat.timestamp().is_some());
+    assert_eq!(Chatfile::get_sender(&lines[2]), Some("agent-1"));
+
+    // Old, unstamped lines still parse
+    assert_eq!(Chatfile::get_sender("agent-2: hi"), Some("agent-2"));
+    assert_eq!(Message::parse("agent-2: hi").timestamp(), None);
+    assert_eq!(
+        Chatfile::get_sender("[2026-01-01 10:00:00] [agent-2 joined]"),
+        None
+    );
+
+    remove_room("test_stamped.Chatfile").unwrap();
+}
+
+#[test]
+fn test_reply_threads() {
+    let _ = remove_room("test_thread.Chatfile");
+
+    let cf = Chatfile::create(Some("test_thread")).unwrap();
+    cf.send("agent-1", "question A").unwrap(); // #2
+    cf.send("agent-2", "question B").unwrap(); // #3
+    cf.send_reply("agent-2", 2, "answer A").unwrap(); // #4
+    cf.send_reply("agent-1", 3, "answer B").unwrap(); // #5
+    cf.send_reply("agent-1", 4, "follow-up A").unwrap(); // #6
+
+    assert_eq!(cf.line_count().unwrap(), 6);
+    assert_eq!(
+        cf.read_last_numbered(2).unwrap(),
+        vec![
+            (5, "agent-1: >>3 answer B".to_string()),
+            (6, "agent-1: >>4 follow-up A".to_string())
+        ]
+    );
+    assert_eq!(
+        thread::reply_to(&Message::parse("agent-1: >>3 answer B")),
+        Some(3)
+    );
+    assert_eq!(thread::reply_to(&Message::parse("agent-1: >>x nope")), None);
+
+    // Counts are cached by offset, both ahead of the cache and behind it
+    assert!(lines::path(&cf.path).exists());
+    cf.send("agent-2", "more").unwrap(); // #7
+    assert_eq!(cf.line_count().unwrap(), 7);
+    assert_eq!(cf.read_last_numbered(3).unwrap()[0].0, 5);
+    fs::remove_file(lines::path(&cf.path)).unwrap();
+    assert_eq!(cf.read_last_numbered(3).unwrap()[0].0, 5);
+
+    // Starting from any message in the thread yields the whole thread
+    for id in [2, 4, 6] {
+        let ids: Vec<u64> = thread::thread(&cf, id)
+            .unwrap()
+            .iter()
+            .map(|(i, _)| *i)
+            .collect();
+        assert_eq!(ids, vec![2, 4, 6]);
+    }
+
+    assert!(matches!(
+        cf.send_reply("agent-1", 99, "nowhere"),
+        Err(Error::UnknownMessage(99))
+    ));
+    assert!(matches!(
+        thread::thread(&cf, 0),
+        Err(Error::UnknownMessage(0))
+    ));
+
+    remove_room("test_thread.Chatfile").unwrap();
+    assert!(!lines::path(&cf.path).exists());
+}
+
+#[test]
+fn test_mentions() {
+    assert_eq!(
+        Chatfile::get_mentions("agent-1: @bob and @swift-fox-1234, see mail@host."),
+        vec!["bob", "swift-fox-1234"]
+    );
+    assert_eq!(
+        Chatfile::get_mentions("[2026-01-01 10:00:00] agent-1: thanks @bob_2."),
+        vec!["bob_2"]
+    );
+    assert!(Chatfile::get_mentions("[admin]: @bob").is_empty());
+    // A mention at the start of a continuation line
+    assert_eq!(Chatfile::get_mentions(r"agent-1: hi\n@bob"), vec!["bob"]);
+
+    let msg = Message::parse("agent-1: @all standup");
+    assert!(msg.mentions_name("bob"));
+    assert!(!Message::parse("agent-1: hi @alice").mentions_name("bob"));
+}
+
+#[test]
+fn test_await_filters() {
+    let _ = remove_room("test_filter.Chatfile");
+    let home = std::env::temp_dir().join("chatfiles_test_filter");
+    let _ = fs::remove_dir_all(&home);
+
+    let cf = Chatfile::create(Some("test_filter")).unwrap();
+    cf_ok(&home, &["register", "test_filter.Chatfile", "-n", "bob"]);
+    cf_ok(&home, &["join"]);
+
+    cf.send("agent-1", "general chatter").unwrap();
+    cf.send("agent-2", "@bob please review").unwrap();
+    cf.send("agent-1", "@alice not you").unwrap();
+    assert_eq!(
+        cf_ok(&home, &["await", "--mentions"]),
+        "agent-2: @bob please review\n"
+    );
+    cf.send("agent-1", "see below\n@bob this one").unwrap();
+    assert!(cf_ok(&home, &["await", "--mentions"]).contains("@bob this one"));
+
+    cf.send("agent-2", "from two").unwrap();
+    cf.send("agent-1", "from one").unwrap();
+    assert_eq!(
+        cf_ok(&home, &["await", "--from", "agent-1"]),
+        "agent-1: from one\n"
+    );
+    assert_eq!(cf_ok(&home, &["await", "--poll"]), "");
+
+    // A timeout too large for a deadline just waits without one
+    cf.send("agent-1", "still there").unwrap();
+    assert_eq!(
+        cf_ok(&home, &["await", "--timeout", "18446744073709551s"]),
+        "agent-1: still there\n"
+    );
+
+    remove_room("test_filter.Chatfile").unwrap();
+    fs::remove_dir_all(&home).unwrap();
+}
+
+#[test]
+fn test_watch_many_rooms() {
+    let _ = remove_room("test_multi_a.Chatfile");
+    let _ = remove_room("test_multi_b.Chatfile");
+
+    let a = Chatfile::create(Some("test_multi_a")).unwrap();
+    let b = Chatfile::create(Some("test_multi_b")).unwrap();
+    let (a_end, b_end) = (a.size().unwrap(), b.size().unwrap());
+
+    let writer = std::thread::spawn(|| {
+        std::thread::sleep(Duration::from_millis(100));
+        let b = Chatfile::open("test_multi_b.Chatfile").unwrap();
+        b.send("worker", "done").unwrap();
+    });
+
+    let deadline = Instant::now() + Duration::from_secs(10);
+    let (room, lines, next) =
+        Chatfile::watch_many(&[(&a, a_end), (&b, b_end)], Some(deadline)).unwrap();
+    writer.join().unwrap();
+
+    assert_eq!(room, 1);
+    assert_eq!(lines, vec!["worker: done"]);
+    assert_eq!(next, b.size().unwrap());
+
+    remove_room("test_multi_a.Chatfile").unwrap();
+    remove_room("test_multi_b.Chatfile").unwrap();
+}
+
+#[test]
+fn test_await_rooms_cli() {
+    let _ = remove_room("test_rooms_a.Chatfile");
+    let _ = remove_room("test_rooms_b.Chatfile");
+    let home = std::env::temp_dir().join("chatfiles_test_rooms");
+    let _ = fs::remove_dir_all(&home);
+
+    let a = Chatfile::create(Some("test_rooms_a")).unwrap();
+    let _ = Chatfile::create(Some("test_rooms_b")).unwrap();
+
+    cf_ok(&home, &["register", "test_rooms_a.Chatfile", "-n", "coord"]);
+    cf_ok(&home, &["join"]);
+    a.send("planner", "plan ready").unwrap();
+
+    let rooms = "test_rooms_b.Chatfile,test_rooms_a.Chatfile";
+    let (code, out) = cf(&home, None, &["await", "--rooms", rooms, "--timeout", "5s"]);
+    assert_eq!(code, Some(0));
+    assert!(out.ends_with("test_rooms_a.Chatfile: planner: plan ready\n"));
+
+    // The current room's cursor moved, so nothing is pending any more
+    let (code, _) = cf(
+        &home,
+        None,
+        &["await", "--rooms", rooms, "--timeout", "100ms"],
+    );
+    assert_eq!(code, Some(124));
+
+    remove_room("test_rooms_a.Chatfile").unwrap();
+    remove_room("test_rooms_b.Chatfile").unwrap();
+    fs::remove_dir_all(&home).unwrap();
+}
+
+#[test]
+fn test_await_polling_fallback() {
+    let _ = remove_room("test_pollwatch.Chatfile");
+    let home = std::env::temp_dir().join("chatfiles_test_pollwatch");
+    let _ = fs::remove_dir_all(&home);
+
+    let cf = Chatfile::create(Some("test_pollwatch")).unwrap();
+    cf_ok(
+        &home,
+        &["register", "test_pollwatch.Chatfile", "-n", "remote"],
+    );
+    cf_ok(&home, &["join"]);
+
+    // Without notifications, the append is only seen by polling
+    let waiter = cf_command(&home, None, &["await", "--timeout", "10s"])
+        .env("CF_WATCH", "poll")
+        .env("CF_POLL_INTERVAL", "50ms")
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::null())
+        .spawn()
+        .unwrap();
+    std::thread::sleep(Duration::from_millis(200));
+    cf.send("local", "over nfs").unwrap();
+
+    let out = waiter.wait_with_output().unwrap();
+    assert_eq!(out.status.code(), Some(0));
+    assert_eq!(String::from_utf8(out.stdout).unwrap(), "local: over nfs\n");
+
+    remove_room("test_pollwatch.Chatfile").unwrap();
+    fs::remove_dir_all(&home).unwrap();
+}
+
+#[test]
+fn test_watch_detects_rotation() {
+    let _ = remove_room("test_rotate.Chatfile");
+
+    let cf = Chatfile::create(Some("test_rotate")).unwrap();
+    // Rotating an append-only room takes clearing the flag first
+    protect::unprotect(&cf.path).unwrap();
+    cf.send("agent-1", "before").unwrap();
+    let end = cf.size().unwrap();
+
+    // Atomically replaced by a shorter file
+    let replacer = std::thread::spawn(|| {
+        std::thread::sleep(Duration::from_millis(100));
+        fs::write(
+            "test_rotate.Chatfile.tmp",
+            "[system 2026-01-01 00:00:00]: new\n",
+        )
+        .unwrap();
+        fs::rename("test_rotate.Chatfile.tmp", "test_rotate.Chatfile").unwrap();
+    });
+    let deadline = Some(Instant::now() + Duration::from_secs(10));
+    assert!(matches!(
+        cf.watch_from(end, deadline),
+        Err(Error::RoomRotated(_))
+    ));
+    replacer.join().unwrap();
+
+    // A cursor past the end means the file was truncated
+    assert!(matches!(
+        cf.watch_from(end + 1000, deadline),
+        Err(Error::RoomRotated(_))
+    ));
+
+    let end = cf.size().unwrap();
+    let deleter = std::thread::spawn(|| {
+        std::thread::sleep(Duration::from_millis(100));
+        remove_room("test_rotate.Chatfile").unwrap();
+    });
+    assert!(matches!(
+        cf.watch_from(end, deadline),
+        Err(Error::RoomClosed(_))
+    ));
+    deleter.join().unwrap();
+}
+
+#[test]
+fn test_await_after_replacement() {
+    let _ = remove_room("test_replaced.Chatfile");
+    let home = std::env::temp_dir().join("chatfiles_test_replaced");
+    let _ = fs::remove_dir_all(&home);
+
+    let room = Chatfile::create(Some("test_replaced")).unwrap();
+    protect::unprotect(&room.path).unwrap();
+    cf_ok(&home, &["register", "test_replaced.Chatfile", "-n", "bob"]);
+    cf_ok(&home, &["join"]);
+    room.send("agent-1", "before").unwrap();
+    assert_eq!(cf_ok(&home, &["await", "--poll"]), "agent-1: before\n");
+
+    // Replaced between two awaits by a file longer than the cursor
+    let mut content = String::from("[system 2026-01-01 00:00:00]: new\n");
+    for i in 0..20 {
+        content.push_str(&format!("agent-2: line {i} of the new room\n"));
+    }
+    fs::write("test_replaced.Chatfile.tmp", &content).unwrap();
+    fs::rename("test_replaced.Chatfile.tmp", &room.path).unwrap();
+
+    assert_eq!(cf(&home, None, &["await", "--poll"]).0, Some(23));
+    let out = cf_ok(&home, &["await", "--poll"]);
+    assert!(out.starts_with("agent-2: line 0 of the new room\n"));
+    assert_eq!(out.lines().count(), 20);
+
+    remove_room("test_replaced.Chatfile").unwrap();
+    fs::remove_dir_all(&home).unwrap();
+}
+
+#[test]
+fn test_exit_codes() {
+    let _ = remove_room("test_exit.Chatfile");
+    let home = std::env::temp_dir().join("chatfiles_test_exit");
+    let _ = fs::remove_dir_all(&home);
+
+    let cf_code = |args: &[&str]| cf(&home, None, args).0;
+
+    assert_eq!(cf_code(&["status"]), Some(10));
+    assert_eq!(cf_code(&["register", "test_exit.Chatfile"]), Some(20));
+    assert_eq!(cf_code(&["create-room", "test_exit"]), Some(0));
+    assert_eq!(cf_code(&["create-room", "test_exit"]), Some(21));
+    assert_eq!(
+        cf_code(&["register", "test_exit.Chatfile", "-n", "bad:name"]),
+        Some(31)
+    );
+    assert_eq!(
+        cf_code(&["register", "test_exit.Chatfile", "-n", "exiter"]),
+        Some(0)
+    );
+    assert_eq!(cf_code(&["send", "hello"]), Some(12));
+    assert_eq!(cf_code(&["join"]), Some(0));
+    assert_eq!(cf_code(&["join"]), Some(13));
+    assert_eq!(cf_code(&["send", ""]), Some(30));
+    assert_eq!(cf_code(&["read", "--thread", "999"]), Some(33));
+    assert_eq!(cf_code(&["read", "--bogus"]), Some(2));
+
+    // The session still shows once its room is gone
+    remove_room("test_exit.Chatfile").unwrap();
+    let (code, status) = cf(&home, None, &["status"]);
+    assert_eq!(code, Some(0));
+    assert!(status.starts_with("Session: exiter\n"));
+    assert!(status.contains("Room: unavailable"));
+
+    let garbage = home.join("garbage.session");
+    fs::write(&garbage, "garbage").unwrap();
+    let corrupt = run(cf_command(&home, None, &["status"]).env("CF_SESSION", &garbage));
+    assert_eq!(corrupt.0, Some(11));
+
+    fs::remove_dir_all(&home).unwrap();
+}
+
+#[test]
+fn test_json_output() {
+    let _ = remove_room("test_json.Chatfile");
+    let home = std::env::temp_dir().join("chatfiles_test_json");
+    let _ = fs::remove_dir_all(&home);
+
+    let cf_json = |args: &[&str]| {
+        let (code, out) = cf(&home, None, &[&["--json"], args].concat());
+        let value: serde_json::Value = serde_json::from_str(&out).unwrap();
+        (code, value)
+    };
+
+    let (code, err) = cf_json(&["status"]);
+    assert_eq!(code, Some(10));
+    assert_eq!(err["error"]["kind"], "no_session");
+    assert_eq!(err["error"]["code"], 10);
+
+    let (code, room) = cf_json(&["create-room", "test_json"]);
+    assert_eq!(code, Some(0));
+    assert!(
+        room["room"]
+            .as_str()
+            .unwrap()
+            .ends_with("test_json.Chatfile")
+    );
+
+    let (_, reg) = cf_json(&["register", "test_json.Chatfile", "-n", "jsoner"]);
+    assert_eq!(reg["name"], "jsoner");
+    assert_eq!(cf_json(&["join"]).1["joined"], true);
+    assert_eq!(cf_json(&["send", "hi @other"]).1["ok"], true);
+
+    let (_, status) = cf_json(&["status"]);
+    assert_eq!(status["name"], "jsoner");
+    assert_eq!(status["joined"], true);
+
+    let (_, read) = cf_json(&["read", "--ids"]);
+    let messages = read["messages"].as_array().unwrap();
+    assert_eq!(messages[0]["type"], "system");
+    assert_eq!(messages[1]["type"], "join");
+    assert_eq!(messages[2]["id"], 3);
+    assert_eq!(messages[2]["name"], "jsoner");
+    assert_eq!(messages[2]["text"], "hi @other");
+    assert_eq!(messages[2]["mentions"][0], "other");
+
+    let cf = Chatfile::open("test_json.Chatfile").unwrap();
+    cf.send("other", ">>3 hello\nthere").unwrap();
+    let (_, awaited) = cf_json(&["await", "--poll"]);
+    let reply = &awaited["messages"][0];
+    assert_eq!(reply["reply_to"], 3);
+    assert_eq!(reply["text"], ">>3 hello\nthere");
+    assert_eq!(reply["line"], "other: >>3 hello\\nthere");
+
+    let (code, usage) = cf_json(&["read", "--bogus"]);
+    assert_eq!(code, Some(2));
+    assert_eq!(usage["error"]["kind"], "usage");
+
+    // A message that reads `--json` doesn't ask for JSON errors
+    let (code, out) = run(&mut cf_command(
+        &home,
+        None,
+        &["read", "--bogus", "--", "--json"],
+    ));
+    assert_eq!(code, Some(2));
+    assert!(out.is_empty());
+    let (code, out) = run(&mut cf_command(&home, None, &["--json=true", "read"]));
+    assert_eq!(code, Some(2));
+    let usage: serde_json::Value = serde_json::from_str(&out).unwrap();
+    assert_eq!(usage["error"]["kind"], "usage");
+    let (code, out) = run(&mut cf_command(&home, None, &["--json=false", "read"]));
+    assert_eq!(code, Some(2));
+    assert!(out.is_empty());
+
+    remove_room("test_json.Chatfile").unwrap();
+    fs::remove_dir_all(&home).unwrap();
+}
+
+#[test]
+fn test_session_selection() {
+    let _ = remove_room("test_select_a.Chatfile");
+    let _ = remove_room("test_select_b.Chatfile");
+    let home = std::env::temp_dir().join("chatfiles_test_select");
+    let _ = fs::remove_dir_all(&home);
+    let work = home.join("work");
+    fs::create_dir_all(work.join("sub")).unwrap();
+
+    let room_a = Chatfile::create(Some("test_select_a")).unwrap().path;
+    let room_b = Chatfile::create(Some("test_select_b")).unwrap().path;
+    let room_a = fs::canonicalize(room_a).unwrap();
+    let room_b = fs::canonicalize(room_b).unwrap();
+
+    let cf_in = |dir: &Path, args: &[&str]| run(cf_command(&home, None, args).current_dir(dir));
+    let cf = |args: &[&str]| cf_in(&work, args);
+    let status_name = |dir: &Path, args: &[&str]| {
+        let args = [args, &["--json", "status"]].concat();
+        let (_, out) = cf_in(dir, &args);
+        let value: serde_json::Value = serde_json::from_str(&out).unwrap();
+        value["name"].as_str().unwrap_or_default().to_string()
+    };
+
+    let a = room_a.to_str().unwrap();
+    let b = room_b.to_str().unwrap();
+    assert_eq!(cf(&["register", a, "-n", "alice"]).0, Some(0));
+    std::thread::sleep(Duration::from_millis(20));
+    assert_eq!(cf(&["register", b, "-n", "bob"]).0, Some(0));
+
+    // Without a selection the newest session wins
+    assert_eq!(status_name(&work, &[]), "bob");
+    assert_eq!(status_name(&work, &["--session", "alice"]), "alice");
+    assert_eq!(status_name(&work, &["--room", a]), "alice");
+
+    // Pins apply to the directory and below
+    assert_eq!(cf(&["use", "alice"]).0, Some(0));
+    assert_eq!(status_name(&work, &[]), "alice");
+    assert_eq!(status_name(&work.join("sub"), &[]), "alice");
+    assert_eq!(status_name(&home, &[]), "bob");
+    assert_eq!(status_name(&work, &["--session", "bob"]), "bob");
+    assert_eq!(cf(&["use", "--unset"]).0, Some(0));
+    assert_eq!(status_name(&work, &[]), "bob");
+
+    let (code, export) = cf(&["use", "--shell", "--room", a]);
+    assert_eq!(code, Some(0));
+    assert!(export.starts_with("export CF_SESSION='"));
+    assert!(export.trim_end().ends_with(".session'"));
+
+    // Same name in two rooms needs --room or an id
+    assert_eq!(cf(&["register", b, "-n", "alice"]).0, Some(0));
+    assert_eq!(cf(&["--session", "alice", "status"]).0, Some(15));
+    assert_eq!(
+        cf(&["--session", "alice", "--room", b, "status"]).0,
+        Some(0)
+    );
+    assert_eq!(cf(&["--session", "nobody", "status"]).0, Some(14));
+
+    let (_, list) = cf(&["--json", "sessions"]);
+    let list: serde_json::Value = serde_json::from_str(&list).unwrap();
+    let sessions = list["sessions"].as_array().unwrap();
+    assert_eq!(sessions.len(), 3);
+    let id = sessions[0]["id"].as_str().unwrap();
+    let expected = sessions[0]["name"].as_str().unwrap();
+    assert_eq!(status_name(&work, &["--session", &id[..8]]), expected);
+
+    // An exact id wins over a session that happens to be named like it
+    let id = id.to_string();
+    assert_eq!(cf(&["register", a, "-n", &id]).0, Some(0));
+    assert_eq!(status_name(&work, &["--session", &id]), expected);
+    assert_eq!(cf(&["use", &id]).0, Some(0));
+    assert_eq!(status_name(&work, &[]), expected);
+
+    remove_room("test_select_a.Chatfile").unwrap();
+    remove_room("test_select_b.Chatfile").unwrap();
+    fs::remove_dir_all(&home).unwrap();
+}
+
+#[test]
+fn test_session_lifecycle() {
+    let _ = remove_room("test_lifecycle.Chatfile");
+    let _ = remove_room("test_lifecycle_gone.Chatfile");
+    let home = std::env::temp_dir().join("chatfiles_test_lifecycle");
+    let _ = fs::remove_dir_all(&home);
+
+    let room = Chatfile::create(Some("test_lifecycle")).unwrap();
+    let gone = Chatfile::create(Some("test_lifecycle_gone")).unwrap();
+
+    let cf = |args: &[&str]| cf(&home, None, args);
+    let session_count = || {
+        let (_, out) = cf(&["--json", "sessions"]);
+        let list: serde_json::Value = serde_json::from_str(&out).unwrap();
+        list["sessions"].as_array().unwrap().len()
+    };
+
+    cf(&["register", "test_lifecycle.Chatfile", "-n", "old-name"]);
+    cf(&["join"]);
+    assert_eq!(cf(&["rename", "new-name"]).0, Some(0));
+    assert_eq!(cf(&["status"]).1.lines().next(), Some("Session: new-name"));
+    assert_eq!(cf(&["rename", "new-name"]).0, Some(34));
+    assert_eq!(
+        Message::parse(&room.last_line().unwrap().unwrap()),
+        Message::Rename {
+            from: "old-name".into(),
+            to: "new-name".into(),
+            timestamp: None
+        }
+    );
+    assert_eq!(session_count(), 1);
+
+    assert_eq!(cf(&["unregister"]).0, Some(0));
+    assert_eq!(room.last_line().unwrap().unwrap(), "[new-name left]");
+    assert_eq!(session_count(), 0);
+    assert_eq!(cf(&["status"]).0, Some(10));
+
+    // Stale sessions: one for a deleted room, one idle
+    cf(&["register", "test_lifecycle_gone.Chatfile", "-n", "ghost"]);
+    remove_room(&gone.path).unwrap();
+    cf(&["register", "test_lifecycle.Chatfile", "-n", "sleeper"]);
+    std::thread::sleep(Duration::from_millis(50));
+
+    let (_, out) = cf(&["sessions", "gc", "--dry-run"]);
+    assert!(out.contains("Would remove") && out.contains("ghost"));
+    assert!(!out.contains("sleeper"));
+    assert_eq!(session_count(), 2);
+
+    cf(&["sessions", "gc"]);
+    assert_eq!(session_count(), 1);
+    cf(&["sessions", "gc", "--idle", "10ms"]);
+    assert_eq!(session_count(), 0);
+
+    remove_room("test_lifecycle.Chatfile").unwrap();
+    fs::remove_dir_all(&home).unwrap();
+}
+
+#[test]
+fn test_session_format_migration() {
+    let _ = remove_room("test_migrate.Chatfile");
+    let home = std::env::temp_dir().join("chatfiles_test_migrate");
+    let _ = fs::remove_dir_all(&home);
+    fs::create_dir_all(&home).unwrap();
+    let session = home.join("env.session");
+
+    let room = Chatfile::create(Some("test_migrate")).unwrap();
+    let room_path = fs::canonicalize(&room.path).unwrap();
+    let room_path = room_path.to_str().unwrap();
+
+    let cf = |args: &[&str], session_env: Option<&Path>| {
+        let mut cmd = cf_command(&home.join("data"), None, args);
+        cmd.current_dir(&home).env("HOME", &home);
+        if let Some(path) = session_env {
+            cmd.env("CF_SESSION", path);
+        }
+        run(&mut cmd)
+    };
+
+    // Legacy positional file is rewritten in place
+    fs::write(&session, format!("{room_path}\nlegacy\nyes\n42\n")).unwrap();
+    let (code, out) = cf(&["status"], Some(&session));
+    assert_eq!(code, Some(0));
+    assert!(out.contains("Session: legacy") && out.contains("Joined: yes"));
+    let migrated = fs::read_to_string(&session).unwrap();
+    assert_eq!(
+        migrated,
+        format!("version=1\nchatfile={room_path}\nname=legacy\njoined=true\ncursor=42\n")
+    );
+
+    // Unknown keys survive a save
+    fs::write(&session, format!("{migrated}color=blue\n")).unwrap();
+    assert_eq!(cf(&["leave"], Some(&session)).0, Some(0));
+    let saved = fs::read_to_string(&session).unwrap();
+    assert!(saved.contains("joined=false") && saved.ends_with("color=blue\n"));
+
+    // Files from a newer format are refused rather than misread
+    fs::write(&session, "version=99\nchatfile=x\nname=y\n").unwrap();
+    assert_eq!(cf(&["status"], Some(&session)).0, Some(11));
+
+    // Legacy .cf_session in the working directory moves to the sessions dir
+    let legacy = home.join(".cf_session");
+    fs::write(&legacy, format!("{room_path}\nold-timer\n\n")).unwrap();
+    let (code, out) = cf(&["status"], None);
+    assert_eq!(code, Some(0));
+    assert!(out.contains("Session: old-timer"));
+    assert!(!legacy.exists());
+    let (_, list) = cf(&["--json", "sessions"], None);
+    let list: serde_json::Value = serde_json::from_str(&list).unwrap();
+    assert_eq!(list["sessions"][0]["name"], "old-timer");
+
+    remove_room("test_migrate.Chatfile").unwrap();
+    fs::remove_dir_all(&home).unwrap();
+}
+
+#[test]
+fn test_cf_home_override() {
+    let _ = remove_room("test_cfhome.Chatfile");
+    let home = std::env::temp_dir().join("chatfiles_test_cfhome");
+    let _ = fs::remove_dir_all(&home);
+
+    let room = Chatfile::create(Some("test_cfhome")).unwrap();
+    let cf = |args: &[&str]| cf(&home, None, args);
+
+    assert_eq!(
+        cf(&["register", "test_cfhome.Chatfile", "-n", "boxed"]).0,
+        Some(0)
+    );
+    let sessions: Vec<_> = fs::read_dir(home.join("sessions")).unwrap().collect();
+    assert_eq!(sessions.len(), 1);
+
+    // Admin rights come from CF_HOME as the config directory
+    assert_eq!(cf(&["admin-send", "hello"]).0, Some(41));
+    fs::write(home.join(".cf_admin"), "ops\n").unwrap();
+    assert_eq!(cf(&["admin-send", "hello"]).0, Some(0));
+    assert_eq!(room.last_line().unwrap().unwrap(), "[ops]: hello");
+
+    let (_, listed) = cf(&["--json", "clear", "--sessions-only", "--dry-run"]);
+    let listed: serde_json::Value = serde_json::from_str(&listed).unwrap();
+    let session_files = listed["sessions"].as_array().unwrap();
+    assert_eq!(session_files.len(), 1);
+    assert!(
+        session_files[0]
+            .as_str()
+            .unwrap()
+            .starts_with(home.to_str().unwrap())
+    );
+
+    remove_room("test_cfhome.Chatfile").unwrap();
+    fs::remove_dir_all(&home).unwrap();
+}
+
+#[test]
+fn test_clear_lists_room_data() {
+    let home = std::env::temp_dir().join("chatfiles_test_clear");
+    let dir = home.join("rooms");
+    let _ = remove_room(dir.join("room.Chatfile"));
+    let _ = fs::remove_dir_all(&home);
+    fs::create_dir_all(&dir).unwrap();
+    let cf = |args: &[&str]| run(cf_command(&home, None, args).current_dir(&dir));
+
+    assert_eq!(cf(&["create-room", "room", "--rate", "5/1m"]).0, Some(0));
+    assert_eq!(cf(&["register", "room.Chatfile", "-n", "ann"]).0, Some(0));
+    assert_eq!(cf(&["join"]).0, Some(0));
+    assert_eq!(cf(&["send", "hello"]).0, Some(0));
+
+    let (_, listed) = cf(&["--json", "clear", "--dry-run"]);
+    let listed: serde_json::Value = serde_json::from_str(&listed).unwrap();
+    let sidecars: Vec<_> = listed["sidecars"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|p| {
+            Path::new(p.as_str().unwrap())
+                .file_name()
+                .unwrap()
+                .to_owned()
+        })
+        .collect();
+    for name in [
+        ".room.Chatfile.presence",
+        ".room.Chatfile.rate",
+        ".room.Chatfile.moderation",
+        ".room.Chatfile.snapshot",
+    ] {
+        assert!(sidecars.iter().any(|s| s == name), "{name} not listed");
+    }
+    let (_, human) = cf(&["clear", "--dry-run"]);
+    assert!(human.contains("Room data:\n"));
+    assert!(human.contains(".room.Chatfile.presence"));
+
+    // Everything listed is removed, and nothing is left behind
+    let (code, cleared) = cf(&["--json", "clear", "--force"]);
+    assert_eq!(code, Some(0));
+    let cleared: serde_json::Value = serde_json::from_str(&cleared).unwrap();
+    assert_eq!(
+        cleared["removed"].as_array().unwrap().len(),
+        1 + sidecars.len() + listed["sessions"].as_array().unwrap().len()
+    );
+    assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+
+    fs::remove_dir_all(&home).unwrap();
+}
+
+#[test]
+fn test_roster() {
+    let _ = remove_room("test_roster.Chatfile");
+    let home = std::env::temp_dir().join("chatfiles_test_roster");
+    let room = Chatfile::create(Some("test_roster")).unwrap();
+
+    room.announce_join("alpha").unwrap();
+    room.announce_join("beta").unwrap();
+    room.append("beta: [gamma joined] is not a join").unwrap();
+    room.announce_join("gamma").unwrap();
+    room.announce_leave("alpha").unwrap();
+    room.announce_rename("beta", "delta").unwrap();
+    room.announce_join("alpha").unwrap();
+
+    let roster = room.members().unwrap();
+    let names: Vec<_> = roster.iter().map(|m| m.name.as_str()).collect();
+    assert_eq!(names, ["delta", "gamma", "alpha"]);
+    assert_eq!(roster[0].joined_id, 3);
+    assert_eq!(roster[2].joined_id, 8);
+    assert!(roster.iter().all(|m| m.joined_at.is_none()));
+
+    let out = cf_ok(&home, &["--json", "members", "test_roster.Chatfile"]);
+    let json: serde_json::Value = serde_json::from_str(&out).unwrap();
+    assert_eq!(json["members"][1]["name"], "gamma");
+    assert_eq!(json["members"][1]["joined_id"], 5);
+
+    remove_room("test_roster.Chatfile").unwrap();
+    let _ = fs::remove_dir_all(&home);
+}
+
+#[test]
+fn test_roles() {
+    let _ = remove_room("test_roles.Chatfile");
+    let home = std::env::temp_dir().join("chatfiles_test_roles");
+    let _ = fs::remove_dir_all(&home);
+
+    let room = Chatfile::create(Some("test_roles")).unwrap();
+    let cf = |session: &str, args: &[&str]| cf(&home, Some(session), args);
+    for name in ["alice", "bob"] {
+        cf(name, &["register", "test_roles.Chatfile", "-n", name]);
+        cf(name, &["join"]);
+    }
+
+    // Without an owner, setting up roles takes .cf_admin
+    assert_eq!(
+        cf("alice", &["role", "grant", "alice", "owner"]).0,
+        Some(41)
+    );
+    fs::write(home.join(".cf_admin"), "").unwrap();
+    assert_eq!(cf("alice", &["role", "grant", "alice", "owner"]).0, Some(0));
+    assert_eq!(cf("bob", &["role", "grant", "bob", "owner"]).0, Some(40));
+    assert_eq!(cf("alice", &["role", "grant", "bob", "chief"]).0, Some(2));
+    assert_eq!(
+        cf("alice", &["role", "grant", "bob", "observer"]).0,
+        Some(0)
+    );
+
+    // Observers read, but never write
+    cf("bob", &["leave"]);
+    assert_eq!(cf("bob", &["join"]).0, Some(0));
+    assert_eq!(cf("bob", &["send", "hi"]).0, Some(40));
+    assert_eq!(cf("bob", &["admin-send", "hi"]).0, Some(40));
+    assert_eq!(cf("bob", &["rename", "carol"]).0, Some(0));
+    assert_eq!(cf("alice", &["admin-send", "welcome"]).0, Some(0));
+    let content = fs::read_to_string(&room.path).unwrap();
+    assert_eq!(content.matches("[bob joined]").count(), 1);
+    assert!(!content.contains("[bob left]"));
+    assert!(!content.contains("renamed"));
+
+    let (_, status) = cf("carol", &["--json", "status"]);
+    let status: serde_json::Value = serde_json::from_str(&status).unwrap();
+    assert_eq!(status["role"], "observer");
+    let (_, roles) = cf("alice", &["role"]);
+    assert_eq!(roles, "alice  owner\ncarol  observer\n");
+
+    // A name with a role can't be taken over, even once nobody holds it
+    assert_eq!(
+        cf("alice", &["role", "grant", "dave", "moderator"]).0,
+        Some(0)
+    );
+    assert_eq!(cf("carol", &["rename", "dave"]).0, Some(34));
+    let (_, name) = cf("dave", &["register", "test_roles.Chatfile", "-n", "dave"]);
+    assert_eq!(name.trim(), "dave_2");
+    assert_eq!(cf("alice", &["role", "revoke", "dave"]).0, Some(0));
+
+    // An observer unregistering leaves no trace either
+    cf("erin", &["register", "test_roles.Chatfile", "-n", "erin"]);
+    cf("alice", &["role", "grant", "erin", "observer"]);
+    cf("erin", &["join"]);
+    let before = fs::read_to_string(&room.path).unwrap();
+    assert_eq!(cf("erin", &["unregister"]).0, Some(0));
+    assert_eq!(fs::read_to_string(&room.path).unwrap(), before);
+    cf("alice", &["role", "revoke", "erin"]);
+
+    assert_eq!(cf("alice", &["role", "revoke", "carol"]).0, Some(0));
+    assert_eq!(cf("carol", &["send", "hi"]).0, Some(0));
+    // Revoking the last role opens the room again
+    cf("alice", &["role", "revoke", "alice"]);
+    assert!(!acl::path(&room.path).exists());
+
+    remove_room("test_roles.Chatfile").unwrap();
+    fs::remove_dir_all(&home).unwrap();
+}
+
+#[test]
+fn test_moderation() {
+    let _ = remove_room("test_moderation.Chatfile");
+    let home = std::env::temp_dir().join("chatfiles_test_moderation");
+    let _ = fs::remove_dir_all(&home);
+    fs::create_dir_all(&home).unwrap();
+    fs::write(home.join(".cf_admin"), "").unwrap();
+
+    let room = Chatfile::create(Some("test_moderation")).unwrap();
+    let lines = [
+        "[bob was kicked by alice]",
+        "[bob was muted by alice until 2000-01-01 00:00:00]",
+        "[bob was unmuted by alice]",
+        "[2000-01-01 00:00:00] [bob was banned by alice]",
+    ];
+    for line in lines {
+        assert!(matches!(Message::parse(line), Message::Moderation { .. }));
+        assert_eq!(Message::parse(line).to_string(), line);
+    }
+    // An expired mute no longer applies
+    room.append(lines[1]).unwrap();
+    let standing = room.standing("bob").unwrap();
+    assert!(standing.muted.is_some());
+    assert!(
+        standing
+            .muted_at(chrono::Local::now().naive_local())
+            .is_none()
+    );
+
+    let cf = |session: &str, args: &[&str]| cf(&home, Some(session), args).0;
+    for name in ["alice", "bob"] {
+        cf(name, &["register", "test_moderation.Chatfile", "-n", name]);
+        cf(name, &["join"]);
+    }
+
+    assert_eq!(cf("alice", &["mute", "bob", "--for", "1h"]), Some(0));
+    assert_eq!(cf("bob", &["send", "spam"]), Some(43));
+    // Renaming doesn't shake off a mute
+    assert_eq!(cf("bob", &["rename", "bobby"]), Some(0));
+    assert_eq!(cf("bobby", &["send", "spam"]), Some(43));
+    assert_eq!(cf("alice", &["mute", "bobby", "--lift"]), Some(0));
+    assert_eq!(cf("bobby", &["send", "sorry"]), Some(0));
+
+    assert_eq!(cf("alice", &["kick", "bobby"]), Some(0));
+    assert_eq!(cf("bobby", &["send", "hello?"]), Some(42));
+    let names: Vec<_> = room
+        .members()
+        .unwrap()
+        .into_iter()
+        .map(|m| m.name)
+        .collect();
+    assert_eq!(names, ["alice"]);
+    assert_eq!(cf("bobby", &["join"]), Some(0));
+    assert_eq!(cf("bobby", &["send", "back"]), Some(0));
+
+    assert_eq!(cf("alice", &["ban", "bobby"]), Some(0));
+    assert_eq!(cf("bobby", &["send", "hello?"]), Some(44));
+    assert_eq!(cf("bobby", &["join"]), Some(44));
+
+    // Standings are cached; the cache agrees with a replay from scratch
+    let cached = room.standing("bobby").unwrap();
+    assert!(cached.banned_by.is_some());
+    fs::remove_file(moderation::path(&room.path)).unwrap();
+    assert_eq!(room.standing("bobby").unwrap(), cached);
+    assert_eq!(cf("alice", &["ban", "bobby", "--lift"]), Some(0));
+    assert_eq!(cf("bobby", &["join"]), Some(0));
+
+    let content = fs::read_to_string(&room.path).unwrap();
+    assert!(content.contains("[bobby was banned by alice]\n"));
+    assert_eq!(content.matches("spam").count(), 0);
+
+    // A kicked session that unregisters already left, as far as the room
+    // is concerned
+    assert_eq!(cf("alice", &["kick", "bobby"]), Some(0));
+    assert_eq!(cf("bobby", &["unregister"]), Some(0));
+    assert!(
+        !fs::read_to_string(&room.path)
+            .unwrap()
+            .contains("[bobby left]")
+    );
+
+    remove_room("test_moderation.Chatfile").unwrap();
+    assert!(!moderation::path(&room.path).exists());
+    fs::remove_dir_all(&home).unwrap();
+}
+
+#[test]
+fn test_rate_limit() {
+    let _ = remove_room("test_rate.Chatfile");
+    let _ = fs::remove_dir_all(".test_rate.Chatfile.rate");
+    let home = std::env::temp_dir().join("chatfiles_test_rate");
+    let _ = fs::remove_dir_all(&home);
+
+    let options = RoomOptions {
+        rate_limit: Some("2/1h".parse().unwrap()),
+        max_length: Some(10),
+        ..Default::default()
+    };
+    assert!("0/1m".parse::<RateLimit>().is_err());
+    // Overflowing windows are rejected instead of wrapping or panicking
+    assert!("1/99999999999999999d".parse::<RateLimit>().is_err());
+    assert_eq!(
+        duration::parse("18446744073709551615ms"),
+        Ok(Duration::from_millis(u64::MAX))
+    );
+    assert!(duration::parse("18446744073709552s").is_err());
+    assert!(duration::parse("307445734561826m").is_err());
+    let room = Chatfile::create_with(Some("test_rate"), options.clone()).unwrap();
+    let header = fs::read_to_string(&room.path).unwrap();
+    assert!(header.contains("Options: rate 2/1h, max-length 10."));
+    assert_eq!(Chatfile::open(&room.path).unwrap().options, options);
+
+    assert!(matches!(
+        room.send("flood", "far too long"),
+        Err(Error::MessageTooLong(12, 10))
+    ));
+    room.send("flood", "one").unwrap();
+    room.send("flood", "two").unwrap();
+    assert!(matches!(
+        room.send("flood", "three"),
+        Err(Error::RateLimited(limit, retry)) if limit == "2/1h" && retry > 3590
+    ));
+    // Limits are per agent
+    room.send("calm", "hi").unwrap();
+    // The reply marker doesn't count toward max-length
+    room.send_reply("calm", 1, "ten chars!").unwrap();
+    // A send taken back no longer counts
+    let limit = options.rate_limit.unwrap();
+    assert_eq!(rate::used(&room.path, "calm", limit).unwrap(), 2);
+    assert!(matches!(
+        rate::record(&room.path, "calm", limit),
+        Err(Error::RateLimited(..))
+    ));
+    let log = fs::read_to_string(rate::dir(&room.path).join("calm")).unwrap();
+    let last: u64 = log.lines().last().unwrap().parse().unwrap();
+    rate::forget(&room.path, "calm", last).unwrap();
+    assert_eq!(rate::used(&room.path, "calm", limit).unwrap(), 1);
+
+    let cf = |args: &[&str]| cf(&home, None, args);
+    cf(&["register", "test_rate.Chatfile", "-n", "cli"]);
+    cf(&["join"]);
+    assert_eq!(cf(&["send", "one"]).0, Some(0));
+    assert_eq!(cf(&["send", "two"]).0, Some(0));
+    assert_eq!(cf(&["send", "three"]).0, Some(36));
+    let (_, status) = cf(&["status"]);
+    assert!(status.contains("Rate limit: 2/1h (2 used)"));
+    assert!(status.contains("Max length: 10"));
+
+    remove_room("test_rate.Chatfile").unwrap();
+    assert!(!rate::dir(&room.path).exists());
+    fs::remove_dir_all(&home).unwrap();
+}
+
+#[test]
+fn test_hash_chain() {
+    let _ = remove_room("test_chain.Chatfile");
+    let home = std::env::temp_dir().join("chatfiles_test_chain");
+    let options = RoomOptions {
+        hash_chain: true,
+        timestamps: true,
+        ..Default::default()
+    };
+    let room = Chatfile::create_with(Some("test_chain"), options).unwrap();
+    room.announce_join("alice").unwrap();
+    room.send("alice", "first").unwrap();
+    room.send("alice", "second").unwrap();
+
+    // Tags are invisible to everything that parses lines
+    let lines = room.read_last(3).unwrap();
+    assert!(lines.iter().all(|l| chain::split(l).0.is_some()));
+    assert_eq!(Chatfile::get_sender(&lines[1]), Some("alice"));
+    assert_eq!(room.read_messages(1).unwrap()[0].text(), Some("second"));
+    assert!(room.name_exists("alice").unwrap());
+    assert_eq!(room.members().unwrap()[0].name, "alice");
+
+    let verify = |path: &str| cf(&home, None, &["verify", path]);
+    assert_eq!(
+        verify("test_chain.Chatfile"),
+        (Some(0), "Chain intact: 4 lines\n".into())
+    );
+    assert_eq!(chain::verify(&room.path).unwrap(), (4, None));
+
+    protect::unprotect(&room.path).unwrap();
+    let original = fs::read_to_string(&room.path).unwrap();
+    let edited = original.replace("first", "frist");
+    fs::write(&room.path, &edited).unwrap();
+    assert_eq!(
+        chain::verify(&room.path).unwrap().1,
+        Some(chain::Break::Mismatch(3))
+    );
+    assert_eq!(verify("test_chain.Chatfile").0, Some(25));
+
+    let mut reordered: Vec<_> = original.lines().collect();
+    reordered.swap(2, 3);
+    fs::write(&room.path, reordered.join("\n") + "\n").unwrap();
+    assert_eq!(
+        chain::verify(&room.path).unwrap().1,
+        Some(chain::Break::Mismatch(3))
+    );
+
+    fs::write(&room.path, original + "mallory: hi\n").unwrap();
+    assert_eq!(
+        chain::verify(&room.path).unwrap().1,
+        Some(chain::Break::Untagged(5))
+    );
+
+    let _ = remove_room("test_plain.Chatfile");
+    Chatfile::create(Some("test_plain")).unwrap();
+    // Without a chain only the snapshot is checked, which a clean room passes
+    let (code, out) = verify("test_plain.Chatfile");
+    assert_eq!(code, Some(0));
+    assert!(out.contains("no hash chain"));
+
+    remove_room("test_chain.Chatfile").unwrap();
+    remove_room("test_plain.Chatfile").unwrap();
+    let _ = fs::remove_dir_all(&home);
+}
+
+#[test]
+fn test_hash_chain_rename() {
+    let _ = remove_room("test_chain_rename.Chatfile");
+    let home = std::env::temp_dir().join("chatfiles_test_chain_rename");
+    let _ = fs::remove_dir_all(&home);
+
+    let options = RoomOptions {
+        hash_chain: true,
+        ..Default::default()
+    };
+    let room = Chatfile::create_with(Some("test_chain_rename"), options).unwrap();
+    cf_ok(
+        &home,
+        &["register", "test_chain_rename.Chatfile", "-n", "alice"],
+    );
+    cf_ok(&home, &["join"]);
+
+    // Renaming claims the name under the room lock, which appending to a
+    // chained room takes too
+    let mut rename = cf_command(&home, None, &["rename", "bob"])
+        .stdout(std::process::Stdio::null())
+        .spawn()
+        .unwrap();
+    let deadline = Instant::now() + Duration::from_secs(10);
+    let status = loop {
+        if let Some(status) = rename.try_wait().unwrap() {
+            break status;
+        }
+        if Instant::now() > deadline {
+            rename.kill().unwrap();
+            panic!("cf rename hung");
+        }
+        std::thread::sleep(Duration::from_millis(20));
+    };
+    assert!(status.success());
+
+    assert_eq!(
+        Message::parse(&room.last_line().unwrap().unwrap()),
+        Message::Rename {
+            from: "alice".into(),
+            to: "bob".into(),
+            timestamp: None
+        }
+    );
+    assert_eq!(chain::verify(&room.path).unwrap(), (3, None));
+
+    remove_room("test_chain_rename.Chatfile").unwrap();
+    fs::remove_dir_all(&home).unwrap();
+}
+
+#[test]
+fn test_protection() {
+    let path = Path::new("test_protect.Chatfile");
+    if path.exists() {
+        remove_room(path).unwrap();
+    }
+    let home = std::env::temp_dir().join("chatfiles_test_protect");
+    let _ = fs::remove_dir_all(&home);
+
+    let cf = |args: &[&str]| cf(&home, None, args);
+    cf(&["create-room", "test_protect"]);
+    cf(&["register", "test_protect.Chatfile", "-n", "guard"]);
+    cf(&["join"]);
+
+    let level = protect::level(path);
+    let (_, status) = cf(&["--json", "status"]);
+    let status: serde_json::Value = serde_json::from_str(&status).unwrap();
+    assert_eq!(status["protection"], level.as_str());
+    if level == protect::Protection::AppendOnly {
+        // The kernel refuses anything but appends
+        assert!(fs::OpenOptions::new().write(true).open(path).is_err());
+        assert!(fs::OpenOptions::new().append(true).open(path).is_ok());
+        protect::unprotect(path).unwrap();
+        assert_eq!(protect::accept(path).unwrap(), None);
+    }
+    let tampered = || {
+        let (_, status) = cf(&["--json", "status"]);
+        let status: serde_json::Value = serde_json::from_str(&status).unwrap();
+        status["tampered"].as_str().map(str::to_string)
+    };
+    assert_eq!(tampered(), None);
+
+    // Without the flag, truncating the room is recorded by the next send,
+    // which still goes through, and reported until someone accepts it
+    let content = fs::read_to_string(path).unwrap();
+    fs::OpenOptions::new()
+        .write(true)
+        .open(path)
+        .unwrap()
+        .set_len(10)
+        .unwrap();
+    assert_eq!(cf(&["send", "after truncation"]).0, Some(0));
+    assert!(
+        fs::read_to_string(path)
+            .unwrap()
+            .ends_with("guard: after truncation\n")
+    );
+    assert!(tampered().unwrap().contains("shrank"));
+    assert_eq!(cf(&["verify"]).0, Some(25));
+    assert_eq!(cf(&["send", "hi"]).0, Some(0));
+    assert_eq!(cf(&["verify"]).0, Some(25));
+    assert!(tampered().is_some());
+
+    let (code, out) = cf(&["verify", "--accept"]);
+    assert_eq!(code, Some(0));
+    assert!(out.contains("shrank"));
+    assert_eq!(tampered(), None);
+    assert_eq!(cf(&["verify"]).0, Some(0));
+
+    // Replacing it is noticed even by a read-only check
+    fs::write("test_protect.Chatfile.tmp", &content).unwrap();
+    fs::rename("test_protect.Chatfile.tmp", path).unwrap();
+    assert!(tampered().unwrap().contains("replaced"));
+    assert_eq!(cf(&["verify"]).0, Some(25));
+    assert_eq!(cf(&["verify", "--accept"]).0, Some(0));
+    assert_eq!(cf(&["verify"]).0, Some(0));
+
+    // Reading records it too, without failing
+    let snapshot = protect::snapshot_path(path);
+    fs::OpenOptions::new()
+        .write(true)
+        .open(path)
+        .unwrap()
+        .set_len(10)
+        .unwrap();
+    assert_eq!(cf(&["read"]).0, Some(0));
+    assert!(fs::read_to_string(&snapshot).unwrap().contains("tampered="));
+
+    assert!(snapshot.exists());
+    remove_room(path).unwrap();
+    assert!(!snapshot.exists());
+    fs::remove_dir_all(&home).unwrap();
+}
+
+#[test]
+fn test_presence() {
+    let _ = remove_room("test_presence.Chatfile");
+    let _ = fs::remove_dir_all(".test_presence.Chatfile.presence");
+    let home = std::env::temp_dir().join("chatfiles_test_presence");
+    let _ = fs::remove_dir_all(&home);
+
+    let room = Chatfile::create(Some("test_presence")).unwrap();
+    let presence_dir = presence::dir(&room.path);
+    assert!(presence_dir.ends_with(".test_presence.Chatfile.presence"));
+
+    let cf = |args: &[&str]| cf(&home, None, args);
+
+    cf(&["register", "test_presence.Chatfile", "-n", "live"]);
+    assert_eq!(cf(&["heartbeat"]).0, Some(12));
+    cf(&["join"]);
+    assert_eq!(cf(&["heartbeat"]).0, Some(0));
+
+    // A name that isn't a safe file name, and an agent that died long ago
+    presence::heartbeat(&room, "odd/name").unwrap();
+    fs::write(presence_dir.join("ghost"), "name=ghost\nseen=1000\n").unwrap();
+
+    let members = presence::members(&room.path).unwrap();
+    let status = |name: &str| members.iter().find(|m| m.name == name).map(|m| m.status);
+    assert_eq!(members.len(), 3);
+    assert_eq!(status("live"), Some(presence::Status::Joined));
+    assert_eq!(status("odd/name"), Some(presence::Status::Joined));
+    assert_eq!(status("ghost"), Some(presence::Status::Stale));
+    assert_eq!(members.last().unwrap().name, "ghost");
+
+    let (_, who) = cf(&["who"]);
+    assert!(who.contains("live  joined  seen 0s ago"));
+    assert!(who.contains("ghost  stale"));
+
+    cf(&["leave"]);
+    let (_, who) = cf(&["--json", "who", "test_presence.Chatfile"]);
+    let who: serde_json::Value = serde_json::from_str(&who).unwrap();
+    let names: Vec<_> = who["members"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|m| m["name"].as_str().unwrap())
+        .collect();
+    assert_eq!(names, ["odd/name", "ghost"]);
+
+    remove_room("test_presence.Chatfile").unwrap();
+    assert!(!presence_dir.exists());
+    fs::remove_dir_all(&home).unwrap();
+}